@@ -0,0 +1,182 @@
+use std::io::{BufRead, IsTerminal};
+use std::path::PathBuf;
+
+use bytes::Bytes;
+use clap::Parser;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+
+use truskawka_lib::client::{Client, ClientError};
+use truskawka_lib::protocol::{Response, Value};
+
+#[derive(Parser, Debug)]
+#[command(about = "Interactive client for the truskawka key-value server")]
+struct Args {
+    #[arg(long, default_value = "127.0.0.1")]
+    host: String,
+    #[arg(short, long, default_value_t = 7379)]
+    port: u16,
+    #[arg(long, help = "File to keep interactive history in")]
+    history: Option<PathBuf>,
+    #[arg(help = "Command to execute instead of starting the prompt")]
+    command: Vec<String>,
+}
+
+#[derive(thiserror::Error, Debug)]
+enum CliError {
+    #[error("{source}")]
+    Client {
+        #[from]
+        source: ClientError,
+    },
+    #[error("{source}")]
+    Readline {
+        #[from]
+        source: ReadlineError,
+    },
+    #[error("{source}")]
+    IOError {
+        #[from]
+        source: std::io::Error,
+    },
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args).await {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(args: Args) -> Result<(), CliError> {
+    let address = format!("{}:{}", args.host, args.port);
+    let mut client = Client::connect(address.as_str()).await?;
+    if !args.command.is_empty() {
+        let strings = args.command.into_iter().map(Bytes::from).collect();
+        println!("{}", format_response(&client.execute(strings).await?));
+    } else if std::io::stdin().is_terminal() {
+        let history = args.history.or_else(default_history_path);
+        repl(&mut client, &address, history).await?;
+    } else {
+        for line in std::io::stdin().lock().lines() {
+            if let Some(output) = execute_line(&mut client, &line?).await? {
+                println!("{}", output);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn repl(
+    client: &mut Client,
+    address: &str,
+    history: Option<PathBuf>,
+) -> Result<(), CliError> {
+    let mut editor = DefaultEditor::new()?;
+    if let Some(history) = &history {
+        let _ = editor.load_history(history);
+    }
+    let prompt = format!("{}> ", address);
+    loop {
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted | ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        if matches!(line.trim(), "quit" | "exit") {
+            break;
+        }
+        if !line.trim().is_empty() {
+            editor.add_history_entry(line.as_str())?;
+        }
+        if let Some(output) = execute_line(client, &line).await? {
+            println!("{}", output);
+        }
+    }
+    if let Some(history) = &history {
+        if let Err(e) = editor.save_history(history) {
+            eprintln!("warning: failed saving history: {}", e);
+        }
+    }
+    Ok(())
+}
+
+fn default_history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".truskawka_history"))
+}
+
+async fn execute_line(client: &mut Client, line: &str) -> Result<Option<String>, ClientError> {
+    let words = match shell_words::split(line) {
+        Ok(words) => words,
+        Err(e) => return Ok(Some(format!("(error) {}", e))),
+    };
+    if words.is_empty() {
+        return Ok(None);
+    }
+    let strings = words.into_iter().map(Bytes::from).collect();
+    let response = client.execute(strings).await?;
+    Ok(Some(format_response(&response)))
+}
+
+fn format_response(response: &Response) -> String {
+    format!(
+        "[{:?}] {}",
+        response.status_code,
+        format_value(&response.value, 0)
+    )
+}
+
+fn format_value(value: &Value, indent: usize) -> String {
+    match value {
+        Value::Nil => "(nil)".to_string(),
+        Value::Error(message) => format!("(error) {}", String::from_utf8_lossy(message)),
+        Value::String(string) => format!("\"{}\"", string.escape_ascii()),
+        Value::Int(int) => format!("(integer) {}", int),
+        Value::Double(double) => format!("(double) {}", double),
+        Value::Array(values) if values.is_empty() => "(empty array)".to_string(),
+        Value::Array(values) => {
+            let width = values.len().to_string().len();
+            let mut lines = Vec::with_capacity(values.len());
+            for (i, value) in values.iter().enumerate() {
+                let prefix = format!("{:>width$}) ", i + 1, width = width);
+                let value = format_value(value, indent + prefix.len());
+                let padding = if i == 0 { 0 } else { indent };
+                lines.push(format!("{:padding$}{}{}", "", prefix, value));
+            }
+            lines.join("\n")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use truskawka_lib::protocol::ResponseStatusCode;
+
+    use super::*;
+
+    #[test]
+    fn test_formatting_scalars() {
+        let response = Response::ok(Value::String(Bytes::from_static(b"a\"b\n\xff")));
+        assert_eq!(format_response(&response), "[Ok] \"a\\\"b\\n\\xff\"");
+        assert_eq!(format_response(&Response::nx()), "[Nx] (nil)");
+        let response = Response::error(ResponseStatusCode::Err, "bad");
+        assert_eq!(format_response(&response), "[Err] (error) bad");
+        assert_eq!(format_value(&Value::Int(-3), 0), "(integer) -3");
+        assert_eq!(format_value(&Value::Double(1.5), 0), "(double) 1.5");
+    }
+
+    #[test]
+    fn test_formatting_nested_arrays() {
+        let value = Value::Array(vec![
+            Value::String(Bytes::from_static(b"a")),
+            Value::Array(vec![Value::Int(1), Value::Nil]),
+            Value::Array(vec![]),
+        ]);
+        assert_eq!(
+            format_value(&value, 0),
+            "1) \"a\"\n2) 1) (integer) 1\n   2) (nil)\n3) (empty array)"
+        );
+    }
+}
@@ -1,3 +1,79 @@
-fn main() {
-    println!("Hello, world!");
+use std::path::PathBuf;
+
+use clap::Parser;
+use tokio::net::TcpListener;
+
+use truskawka_lib::aof::{AofConfig, FsyncPolicy};
+use truskawka_lib::db::{Db, Persistence};
+use truskawka_lib::protocol::RequestLimits;
+use truskawka_lib::replication;
+use truskawka_lib::server::{self, Config};
+
+#[derive(Parser, Debug)]
+#[command(about = "truskawka key-value server")]
+struct Args {
+    #[arg(long, default_value = "127.0.0.1")]
+    bind: String,
+    #[arg(short, long, default_value_t = 7379)]
+    port: u16,
+    #[arg(long, default_value_t = RequestLimits::default().max_strings)]
+    max_request_strings: usize,
+    #[arg(long, default_value_t = RequestLimits::default().max_string_len)]
+    max_string_length: usize,
+    #[arg(long, default_value_t = RequestLimits::default().max_frame_len)]
+    max_request_size: usize,
+    #[arg(long)]
+    strict_ascii: bool,
+    #[arg(long, default_value = "truskawka.snapshot")]
+    snapshot: PathBuf,
+    #[arg(long)]
+    appendonly: bool,
+    #[arg(long, default_value = "truskawka.aof")]
+    appendfilename: PathBuf,
+    #[arg(long, default_value = "everysec")]
+    appendfsync: FsyncPolicy,
+    #[arg(long, default_value_t = 100)]
+    auto_aof_rewrite_percentage: u64,
+    #[arg(long, default_value_t = 64 * 1024 * 1024)]
+    auto_aof_rewrite_min_size: u64,
+    #[arg(long)]
+    replicaof: Option<String>,
+    #[arg(long, default_value_t = replication::DEFAULT_BACKLOG_SIZE)]
+    repl_backlog_size: usize,
+    #[arg(long, default_value_t = replication::DEFAULT_OUTPUT_LIMIT)]
+    repl_output_limit: usize,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let args = Args::parse();
+    let config = Config {
+        request_limits: RequestLimits {
+            max_strings: args.max_request_strings,
+            max_string_len: args.max_string_length,
+            max_frame_len: args.max_request_size,
+        },
+        strict_ascii: args.strict_ascii,
+        replica_of: args.replicaof,
+        replication_backlog_size: args.repl_backlog_size,
+        replication_output_limit: args.repl_output_limit,
+    };
+    let db = Db::open(Persistence {
+        snapshot_path: Some(args.snapshot),
+        aof: args.appendonly.then_some(AofConfig {
+            rewrite_percentage: args.auto_aof_rewrite_percentage,
+            rewrite_min_size: args.auto_aof_rewrite_min_size,
+            ..AofConfig::new(args.appendfilename, args.appendfsync)
+        }),
+    })?;
+    let listener = TcpListener::bind((args.bind.as_str(), args.port)).await?;
+    log::info!("Listening on {}", listener.local_addr()?);
+    tokio::select! {
+        _ = server::run(listener, db, config) => {}
+        _ = tokio::signal::ctrl_c() => {
+            log::info!("Shutting down");
+        }
+    }
+    Ok(())
 }
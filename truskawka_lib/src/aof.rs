@@ -0,0 +1,572 @@
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+
+use bytes::{Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::db::Object;
+use crate::protocol::{InvalidRequestError, Request, RequestCodec};
+use crate::snapshot::SnapshotEntry;
+
+const REWRITE_ITEMS_PER_REQUEST: usize = 64;
+const REWRITE_FLUSH_SIZE: usize = 1024 * 1024;
+
+#[derive(thiserror::Error, Debug)]
+pub enum AofError {
+    #[error("IO error")]
+    IOError {
+        #[from]
+        source: std::io::Error,
+    },
+    #[error("invalid request in append-only file")]
+    InvalidRequest {
+        #[from]
+        source: InvalidRequestError,
+    },
+    #[error("append-only file is disabled")]
+    Disabled,
+    #[error("an append-only file rewrite is already in progress")]
+    RewriteInProgress,
+    #[error("failed to write to the append-only file")]
+    WriteFailed { source: std::io::Error },
+    #[error("writes are refused until the append-only file can be written again")]
+    NotWritable,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    Always,
+    EverySec,
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "always" => Ok(FsyncPolicy::Always),
+            "everysec" => Ok(FsyncPolicy::EverySec),
+            "never" | "no" => Ok(FsyncPolicy::Never),
+            _ => Err(format!("unknown fsync policy '{}'", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AofConfig {
+    pub path: PathBuf,
+    pub fsync: FsyncPolicy,
+    pub rewrite_percentage: u64,
+    pub rewrite_min_size: u64,
+}
+
+impl AofConfig {
+    pub fn new(path: PathBuf, fsync: FsyncPolicy) -> Self {
+        AofConfig {
+            path,
+            fsync,
+            rewrite_percentage: 100,
+            rewrite_min_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+struct Inner {
+    file: File,
+    size: u64,
+    base_size: u64,
+    rewrite_buffer: Option<BytesMut>,
+    pending: BytesMut,
+}
+
+impl Inner {
+    // Bytes that could not be written stay pending. Before they are written
+    // again, whatever part of them made it to the file is cut off, so a failed
+    // write never leaves a torn frame in the middle of the log.
+    fn write_pending(&mut self, fsync: FsyncPolicy, recovering: bool) -> std::io::Result<()> {
+        if recovering {
+            self.file.set_len(self.size)?;
+        }
+        self.file.write_all(&self.pending)?;
+        // With `always` the sync happens while the keyspace is locked. That is
+        // what makes a reply to a write mean it is on disk, at the cost of
+        // every client waiting for the sync; `everysec` avoids it.
+        if fsync == FsyncPolicy::Always {
+            self.file.sync_data()?;
+        }
+        self.size += self.pending.len() as u64;
+        if let Some(rewrite_buffer) = &mut self.rewrite_buffer {
+            rewrite_buffer.extend_from_slice(&self.pending);
+        }
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+pub struct AppendOnlyFile {
+    path: PathBuf,
+    fsync: FsyncPolicy,
+    rewrite_percentage: u64,
+    rewrite_min_size: u64,
+    inner: Arc<Mutex<Inner>>,
+    dirty: AtomicBool,
+}
+
+impl AppendOnlyFile {
+    pub fn open(config: &AofConfig) -> Result<(Self, Vec<Request>), AofError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&config.path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        let (requests, valid_len) = decode_requests(&data)?;
+        if valid_len < data.len() {
+            log::warn!(
+                "Truncating {} bytes of a torn frame at the end of {:?}",
+                data.len() - valid_len,
+                config.path
+            );
+            file.set_len(valid_len as u64)?;
+            file.sync_all()?;
+        }
+        let size = valid_len as u64;
+        let aof = AppendOnlyFile {
+            path: config.path.clone(),
+            fsync: config.fsync,
+            rewrite_percentage: config.rewrite_percentage,
+            rewrite_min_size: config.rewrite_min_size,
+            inner: Arc::new(Mutex::new(Inner {
+                file,
+                size,
+                base_size: size,
+                rewrite_buffer: None,
+                pending: BytesMut::new(),
+            })),
+            dirty: AtomicBool::new(false),
+        };
+        Ok((aof, requests))
+    }
+
+    pub fn fsync_policy(&self) -> FsyncPolicy {
+        self.fsync
+    }
+
+    pub fn append(&self, requests: &[Request]) -> Result<(), AofError> {
+        let mut inner = self.inner.lock().unwrap();
+        let recovering = !inner.pending.is_empty();
+        let mut codec = RequestCodec::default();
+        for request in requests {
+            codec.encode(request.clone(), &mut inner.pending)?;
+        }
+        inner
+            .write_pending(self.fsync, recovering)
+            .map_err(|source| AofError::WriteFailed { source })?;
+        if self.fsync == FsyncPolicy::EverySec {
+            self.dirty.store(true, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn replace_file(&self, file: File) -> File {
+        std::mem::replace(&mut self.inner.lock().unwrap().file, file)
+    }
+
+    pub fn check_writable(&self) -> Result<(), AofError> {
+        match self.inner.lock().unwrap().pending.is_empty() {
+            true => Ok(()),
+            false => Err(AofError::NotWritable),
+        }
+    }
+
+    pub fn retry_pending(&self) -> Result<(), AofError> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.pending.is_empty() {
+            return Ok(());
+        }
+        inner
+            .write_pending(self.fsync, true)
+            .map_err(|source| AofError::WriteFailed { source })?;
+        if self.fsync == FsyncPolicy::EverySec {
+            self.dirty.store(true, Ordering::Release);
+        }
+        log::info!("Append-only file {:?} is writable again", self.path);
+        Ok(())
+    }
+
+    pub fn sync_handle(&self) -> std::io::Result<Option<File>> {
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(None);
+        }
+        self.inner.lock().unwrap().file.try_clone().map(Some)
+    }
+
+    pub fn should_rewrite(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        let growth = inner.base_size.saturating_mul(self.rewrite_percentage) / 100;
+        self.rewrite_percentage > 0
+            && inner.rewrite_buffer.is_none()
+            && inner.size >= self.rewrite_min_size
+            && inner.size >= inner.base_size.saturating_add(growth)
+    }
+
+    // The capture must start at the same moment as the rewrite buffer, which
+    // holds as long as the caller keeps writes out until `capture` returns.
+    pub fn rewrite_in_background<F>(
+        &self,
+        capture: impl FnOnce() -> F,
+    ) -> Result<tokio::task::JoinHandle<()>, AofError>
+    where
+        F: Future<Output = Vec<SnapshotEntry>> + Send + 'static,
+    {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.rewrite_buffer.is_some() {
+                return Err(AofError::RewriteInProgress);
+            }
+            if !inner.pending.is_empty() {
+                return Err(AofError::NotWritable);
+            }
+            inner.rewrite_buffer = Some(BytesMut::new());
+        }
+        let capture = capture();
+        let path = self.path.clone();
+        let inner = self.inner.clone();
+        Ok(tokio::spawn(async move {
+            let entries = capture.await;
+            let keys = entries.len();
+            let (target, shared) = (path.clone(), inner.clone());
+            match tokio::task::spawn_blocking(move || rewrite(&target, &shared, &entries)).await {
+                Ok(Ok(size)) => {
+                    log::info!("Rewrote {:?} with {} keys ({} bytes)", path, keys, size);
+                    return;
+                }
+                Ok(Err(e)) => log::error!("Rewriting {:?} failed: {:?}", path, e),
+                Err(e) => log::error!("Append-only file rewrite task failed: {:?}", e),
+            }
+            inner.lock().unwrap().rewrite_buffer = None;
+        }))
+    }
+}
+
+fn rewrite(path: &Path, inner: &Mutex<Inner>, entries: &[SnapshotEntry]) -> Result<u64, AofError> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp_path = path.with_file_name(format!("{}.rewrite", file_name));
+    let mut file = File::create(&temp_path)?;
+    let mut codec = RequestCodec::default();
+    let mut buf = BytesMut::new();
+    for entry in entries {
+        for request in entry_requests(entry) {
+            codec.encode(request, &mut buf)?;
+        }
+        if buf.len() >= REWRITE_FLUSH_SIZE {
+            file.write_all(&buf)?;
+            buf.clear();
+        }
+    }
+    file.write_all(&buf)?;
+    file.sync_data()?;
+    let mut inner = inner.lock().unwrap();
+    let tail = inner.rewrite_buffer.take().unwrap_or_default();
+    file.write_all(&tail)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)?;
+    let size = file.metadata()?.len();
+    inner.file = OpenOptions::new().append(true).open(path)?;
+    inner.size = size;
+    inner.base_size = size;
+    Ok(size)
+}
+
+fn entry_requests(entry: &SnapshotEntry) -> Vec<Request> {
+    let key = &entry.key;
+    let mut requests = match &entry.object {
+        Object::String(value) => vec![request(b"SET", key, [value.clone()])],
+        Object::List(list) => chunked(b"RPUSH", key, list.iter().cloned()),
+        Object::Hash(hash) => chunked(
+            b"HSET",
+            key,
+            hash.iter()
+                .flat_map(|(field, value)| [field.clone(), value.clone()]),
+        ),
+        Object::Set(set) => chunked(b"SADD", key, set.members()),
+        Object::SortedSet(set) => chunked(
+            b"ZADD",
+            key,
+            set.range(0..set.len())
+                .into_iter()
+                .flat_map(|(member, score)| [Bytes::from(score.to_string()), member]),
+        ),
+    };
+    if let Some(when) = entry.expires_at {
+        let timestamp = when.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let timestamp = Bytes::from(timestamp.as_millis().to_string());
+        requests.push(request(b"PEXPIREAT", key, [timestamp]));
+    }
+    requests
+}
+
+fn chunked(
+    name: &'static [u8],
+    key: &Bytes,
+    arguments: impl IntoIterator<Item = Bytes>,
+) -> Vec<Request> {
+    let arguments: Vec<_> = arguments.into_iter().collect();
+    let per_request = match name {
+        b"HSET" | b"ZADD" => REWRITE_ITEMS_PER_REQUEST * 2,
+        _ => REWRITE_ITEMS_PER_REQUEST,
+    };
+    arguments
+        .chunks(per_request)
+        .map(|chunk| request(name, key, chunk.iter().cloned()))
+        .collect()
+}
+
+fn request(
+    name: &'static [u8],
+    key: &Bytes,
+    arguments: impl IntoIterator<Item = Bytes>,
+) -> Request {
+    let mut strings = vec![Bytes::from_static(name), key.clone()];
+    strings.extend(arguments);
+    Request { strings }
+}
+
+fn decode_requests(data: &[u8]) -> Result<(Vec<Request>, usize), AofError> {
+    let mut codec = RequestCodec::default();
+    let mut buf = BytesMut::from(data);
+    let mut requests = Vec::new();
+    let mut valid_len = 0;
+    while let Some(request) = codec.decode(&mut buf)? {
+        requests.push(request);
+        valid_len = data.len() - buf.len();
+    }
+    Ok((requests, valid_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+
+    fn request(strings: &[&'static str]) -> Request {
+        Request {
+            strings: strings
+                .iter()
+                .map(|string| Bytes::from_static(string.as_bytes()))
+                .collect(),
+        }
+    }
+
+    fn config(dir: &tempfile::TempDir) -> AofConfig {
+        AofConfig::new(dir.path().join("appendonly.aof"), FsyncPolicy::Always)
+    }
+
+    #[test]
+    fn test_append_and_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let (aof, requests) = AppendOnlyFile::open(&config(&dir)).unwrap();
+        assert!(requests.is_empty());
+        aof.append(&[request(&["SET", "a", "1"])]).unwrap();
+        aof.append(&[request(&["SET", "b", "2"]), request(&["DEL", "a"])])
+            .unwrap();
+        drop(aof);
+        let (_, requests) = AppendOnlyFile::open(&config(&dir)).unwrap();
+        assert_eq!(
+            requests,
+            vec![
+                request(&["SET", "a", "1"]),
+                request(&["SET", "b", "2"]),
+                request(&["DEL", "a"])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_torn_trailing_frame_is_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let (aof, _) = AppendOnlyFile::open(&config(&dir)).unwrap();
+        aof.append(&[request(&["SET", "a", "1"]), request(&["SET", "b", "2"])])
+            .unwrap();
+        drop(aof);
+        let path = config(&dir).path;
+        let len = std::fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 3).unwrap();
+        drop(file);
+        let (aof, requests) = AppendOnlyFile::open(&config(&dir)).unwrap();
+        assert_eq!(requests, vec![request(&["SET", "a", "1"])]);
+        aof.append(&[request(&["SET", "c", "3"])]).unwrap();
+        drop(aof);
+        let (_, requests) = AppendOnlyFile::open(&config(&dir)).unwrap();
+        assert_eq!(
+            requests,
+            vec![request(&["SET", "a", "1"]), request(&["SET", "c", "3"])]
+        );
+    }
+
+    #[test]
+    fn test_failed_writes_are_kept_and_retried() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = config(&dir).path;
+        let (aof, _) = AppendOnlyFile::open(&config(&dir)).unwrap();
+        aof.append(&[request(&["SET", "a", "1"])]).unwrap();
+        let read_only = File::open(&path).unwrap();
+        let mut writable = std::mem::replace(&mut aof.inner.lock().unwrap().file, read_only);
+        assert!(matches!(
+            aof.append(&[request(&["SET", "b", "2"])]),
+            Err(AofError::WriteFailed { .. })
+        ));
+        assert!(matches!(aof.check_writable(), Err(AofError::NotWritable)));
+        assert!(aof.append(&[request(&["SET", "c", "3"])]).is_err());
+        assert!(aof.retry_pending().is_err());
+        writable.write_all(b"torn").unwrap();
+        aof.inner.lock().unwrap().file = writable;
+        aof.retry_pending().unwrap();
+        aof.check_writable().unwrap();
+        aof.append(&[request(&["SET", "d", "4"])]).unwrap();
+        drop(aof);
+        let (_, requests) = AppendOnlyFile::open(&config(&dir)).unwrap();
+        assert_eq!(
+            requests,
+            vec![
+                request(&["SET", "a", "1"]),
+                request(&["SET", "b", "2"]),
+                request(&["SET", "c", "3"]),
+                request(&["SET", "d", "4"])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_everysec_marks_file_dirty() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = AofConfig {
+            fsync: FsyncPolicy::EverySec,
+            ..config(&dir)
+        };
+        let (aof, _) = AppendOnlyFile::open(&config).unwrap();
+        assert!(aof.sync_handle().unwrap().is_none());
+        aof.append(&[request(&["SET", "a", "1"])]).unwrap();
+        aof.sync_handle().unwrap().unwrap().sync_data().unwrap();
+        assert!(aof.sync_handle().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_rewrite_keeps_writes_made_during_the_rewrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let (aof, _) = AppendOnlyFile::open(&config(&dir)).unwrap();
+        for _ in 0..100 {
+            aof.append(&[request(&["INCR", "counter"])]).unwrap();
+        }
+        let before = std::fs::metadata(&config(&dir).path).unwrap().len();
+        let expires_at = UNIX_EPOCH + std::time::Duration::from_millis(4102444800000);
+        let handle = aof
+            .rewrite_in_background(|| async move {
+                vec![
+                    SnapshotEntry {
+                        key: Bytes::from_static(b"counter"),
+                        object: Object::String(Bytes::from_static(b"100")),
+                        expires_at: Some(expires_at),
+                    },
+                    SnapshotEntry {
+                        key: Bytes::from_static(b"list"),
+                        object: Object::List((0..70).map(|i| Bytes::from(i.to_string())).collect()),
+                        expires_at: None,
+                    },
+                ]
+            })
+            .unwrap();
+        aof.append(&[request(&["SET", "late", "1"])]).unwrap();
+        handle.await.unwrap();
+        aof.append(&[request(&["SET", "later", "2"])]).unwrap();
+        drop(aof);
+        assert!(std::fs::metadata(&config(&dir).path).unwrap().len() < before);
+        let (_, requests) = AppendOnlyFile::open(&config(&dir)).unwrap();
+        let names: Vec<_> = requests
+            .iter()
+            .map(|request| (request.strings[0].clone(), request.strings.len()))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                (Bytes::from_static(b"SET"), 3),
+                (Bytes::from_static(b"PEXPIREAT"), 3),
+                (Bytes::from_static(b"RPUSH"), 66),
+                (Bytes::from_static(b"RPUSH"), 8),
+                (Bytes::from_static(b"SET"), 3),
+                (Bytes::from_static(b"SET"), 3),
+            ]
+        );
+        assert_eq!(
+            requests[1],
+            request(&["PEXPIREAT", "counter", "4102444800000"])
+        );
+        assert_eq!(requests[4], request(&["SET", "late", "1"]));
+        assert_eq!(requests[5], request(&["SET", "later", "2"]));
+    }
+
+    #[tokio::test]
+    async fn test_only_one_rewrite_at_a_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let (aof, _) = AppendOnlyFile::open(&config(&dir)).unwrap();
+        aof.inner.lock().unwrap().rewrite_buffer = Some(BytesMut::new());
+        assert!(matches!(
+            aof.rewrite_in_background(|| async { Vec::new() }),
+            Err(AofError::RewriteInProgress)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_should_rewrite_after_growth() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = AofConfig {
+            rewrite_percentage: 200,
+            rewrite_min_size: 30,
+            ..config(&dir)
+        };
+        let (aof, _) = AppendOnlyFile::open(&config).unwrap();
+        aof.append(&[request(&["SET", "a", "1"])]).unwrap();
+        assert!(!aof.should_rewrite());
+        aof.append(&[request(&["SET", "a", "2"])]).unwrap();
+        assert!(aof.should_rewrite());
+        let entry = SnapshotEntry {
+            key: Bytes::from_static(b"a"),
+            object: Object::String(Bytes::from_static(b"2")),
+            expires_at: None,
+        };
+        aof.rewrite_in_background(|| async move { vec![entry] })
+            .unwrap()
+            .await
+            .unwrap();
+        assert!(!aof.should_rewrite());
+        aof.append(&[request(&["SET", "a", "3"])]).unwrap();
+        assert!(!aof.should_rewrite());
+        aof.append(&[request(&["SET", "a", "4"])]).unwrap();
+        assert!(aof.should_rewrite());
+        let disabled = AofConfig {
+            rewrite_percentage: 0,
+            ..config
+        };
+        let (aof, _) = AppendOnlyFile::open(&disabled).unwrap();
+        assert!(!aof.should_rewrite());
+    }
+
+    #[test]
+    fn test_fsync_policy_from_str() {
+        assert_eq!("always".parse(), Ok(FsyncPolicy::Always));
+        assert_eq!("EVERYSEC".parse(), Ok(FsyncPolicy::EverySec));
+        assert_eq!("no".parse(), Ok(FsyncPolicy::Never));
+        assert!("sometimes".parse::<FsyncPolicy>().is_err());
+    }
+}
@@ -0,0 +1,248 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+use crate::protocol::{
+    InvalidResponseError, Request, RequestCodec, Response, ResponseCodec, ResponseStatusCode, Value,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum ClientError {
+    #[error("error with underlying IO operation")]
+    IOError {
+        #[from]
+        source: std::io::Error,
+    },
+    #[error("server sent an invalid response")]
+    InvalidResponse {
+        #[from]
+        source: InvalidResponseError,
+    },
+    #[error("server closed the connection")]
+    ConnectionClosed,
+    #[error("server responded with {status_code:?}: {message}")]
+    ServerError {
+        status_code: ResponseStatusCode,
+        message: String,
+    },
+    #[error("unexpected response from server: {response:?}")]
+    UnexpectedResponse { response: Response },
+}
+
+#[derive(Debug, Default)]
+pub struct Pipeline {
+    requests: Vec<Request>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, strings: Vec<Bytes>) -> &mut Self {
+        self.requests.push(Request { strings });
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+}
+
+pub struct Client {
+    requests: FramedWrite<OwnedWriteHalf, RequestCodec>,
+    responses: FramedRead<OwnedReadHalf, ResponseCodec>,
+}
+
+impl Client {
+    pub async fn connect(address: impl ToSocketAddrs) -> Result<Client, ClientError> {
+        let socket = TcpStream::connect(address).await?;
+        socket.set_nodelay(true)?;
+        let (reader, writer) = socket.into_split();
+        Ok(Client {
+            requests: FramedWrite::new(writer, RequestCodec::default()),
+            responses: FramedRead::new(reader, ResponseCodec::default()),
+        })
+    }
+
+    pub async fn execute(&mut self, strings: Vec<Bytes>) -> Result<Response, ClientError> {
+        self.requests.send(Request { strings }).await?;
+        match self.responses.next().await {
+            Some(response) => Ok(response?),
+            None => Err(ClientError::ConnectionClosed),
+        }
+    }
+
+    pub async fn pipeline(&mut self, pipeline: Pipeline) -> Result<Vec<Response>, ClientError> {
+        let n_requests = pipeline.len();
+        let send = async {
+            for request in pipeline.requests {
+                self.requests.feed(request).await?;
+            }
+            self.requests.flush().await?;
+            Ok::<_, ClientError>(())
+        };
+        let receive = async {
+            let mut responses = Vec::with_capacity(n_requests);
+            while responses.len() < n_requests {
+                match self.responses.next().await {
+                    Some(response) => responses.push(response?),
+                    None => return Err(ClientError::ConnectionClosed),
+                }
+            }
+            Ok(responses)
+        };
+        let (sent, received) = tokio::join!(send, receive);
+        sent?;
+        received
+    }
+
+    pub async fn get(&mut self, key: impl Into<Bytes>) -> Result<Option<Bytes>, ClientError> {
+        let response = self
+            .execute(vec![Bytes::from_static(b"GET"), key.into()])
+            .await?;
+        match check(response)? {
+            (ResponseStatusCode::Nx, _) => Ok(None),
+            (_, Value::String(value)) => Ok(Some(value)),
+            (status_code, value) => Err(unexpected(status_code, value)),
+        }
+    }
+
+    pub async fn set(
+        &mut self,
+        key: impl Into<Bytes>,
+        value: impl Into<Bytes>,
+    ) -> Result<(), ClientError> {
+        let response = self
+            .execute(vec![Bytes::from_static(b"SET"), key.into(), value.into()])
+            .await?;
+        match check(response)? {
+            (ResponseStatusCode::Ok, _) => Ok(()),
+            (status_code, value) => Err(unexpected(status_code, value)),
+        }
+    }
+
+    pub async fn del<K: Into<Bytes>>(
+        &mut self,
+        keys: impl IntoIterator<Item = K>,
+    ) -> Result<i64, ClientError> {
+        let mut strings = vec![Bytes::from_static(b"DEL")];
+        strings.extend(keys.into_iter().map(Into::into));
+        let response = self.execute(strings).await?;
+        match check(response)? {
+            (_, Value::Int(deleted)) => Ok(deleted),
+            (status_code, value) => Err(unexpected(status_code, value)),
+        }
+    }
+}
+
+fn check(response: Response) -> Result<(ResponseStatusCode, Value), ClientError> {
+    match response {
+        Response {
+            status_code: status_code @ (ResponseStatusCode::Ok | ResponseStatusCode::Nx),
+            value,
+        } => Ok((status_code, value)),
+        Response {
+            status_code,
+            value: Value::Error(message),
+        } => Err(ClientError::ServerError {
+            status_code,
+            message: String::from_utf8_lossy(&message).into_owned(),
+        }),
+        response => Err(ClientError::UnexpectedResponse { response }),
+    }
+}
+
+fn unexpected(status_code: ResponseStatusCode, value: Value) -> ClientError {
+    ClientError::UnexpectedResponse {
+        response: Response { status_code, value },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::db::Db;
+    use crate::server::{self, Config};
+
+    async fn start_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(server::run(listener, Db::new(), Config::default()));
+        address
+    }
+
+    #[tokio::test]
+    async fn test_typed_commands() {
+        let mut client = Client::connect(start_server().await).await.unwrap();
+        assert_eq!(client.get("key").await.unwrap(), None);
+        client.set("key", "strawberry").await.unwrap();
+        assert_eq!(
+            client.get("key").await.unwrap(),
+            Some(Bytes::from_static(b"strawberry"))
+        );
+        assert_eq!(client.del(["key", "other"]).await.unwrap(), 1);
+        assert_eq!(client.del(["key"]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_execute_raw_request() {
+        let mut client = Client::connect(start_server().await).await.unwrap();
+        let response = client
+            .execute(vec![Bytes::from_static(b"NOPE")])
+            .await
+            .unwrap();
+        assert_eq!(response.status_code, ResponseStatusCode::Err);
+    }
+
+    #[tokio::test]
+    async fn test_server_errors_are_reported() {
+        let mut client = Client::connect(start_server().await).await.unwrap();
+        let error = client.del(Vec::<Bytes>::new()).await.unwrap_err();
+        assert!(matches!(
+            error,
+            ClientError::ServerError {
+                status_code: ResponseStatusCode::Err,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_returns_responses_in_order() {
+        let mut client = Client::connect(start_server().await).await.unwrap();
+        let mut pipeline = Pipeline::new();
+        for i in 0..10_000 {
+            pipeline.add(vec![
+                Bytes::from_static(b"SET"),
+                Bytes::from(format!("key{}", i)),
+                Bytes::from(format!("value{}", i)),
+            ]);
+        }
+        for i in 0..10_000 {
+            pipeline.add(vec![
+                Bytes::from_static(b"GET"),
+                Bytes::from(format!("key{}", i)),
+            ]);
+        }
+        let responses = client.pipeline(pipeline).await.unwrap();
+        assert_eq!(responses.len(), 20_000);
+        for (i, response) in responses[10_000..].iter().enumerate() {
+            assert_eq!(
+                response.value,
+                Value::String(Bytes::from(format!("value{}", i)))
+            );
+        }
+        assert!(client.pipeline(Pipeline::new()).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,2812 @@
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+
+use crate::aof::AofError;
+use crate::db::{Db, Keyspace, Object, WrongType};
+use crate::dict::Dict;
+use crate::glob;
+use crate::protocol::{Request, Response, ResponseStatusCode, Value};
+use crate::set::Set;
+use crate::snapshot::SnapshotError;
+use crate::sorted_set::{ScoreBound, SortedSet};
+
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Get {
+        key: Bytes,
+    },
+    Set {
+        key: Bytes,
+        value: Bytes,
+        ttl: Option<Duration>,
+    },
+    Del {
+        keys: Vec<Bytes>,
+    },
+    IncrBy {
+        key: Bytes,
+        increment: i64,
+    },
+    IncrByFloat {
+        key: Bytes,
+        increment: f64,
+    },
+    Expire {
+        key: Bytes,
+        seconds: i64,
+    },
+    PExpire {
+        key: Bytes,
+        milliseconds: i64,
+    },
+    PExpireAt {
+        key: Bytes,
+        timestamp: i64,
+    },
+    Ttl {
+        key: Bytes,
+    },
+    PTtl {
+        key: Bytes,
+    },
+    Persist {
+        key: Bytes,
+    },
+    Save,
+    BgSave,
+    BgRewriteAof,
+    Role,
+    Scan {
+        cursor: u64,
+        pattern: Option<Bytes>,
+        count: usize,
+    },
+    LPush {
+        key: Bytes,
+        values: Vec<Bytes>,
+    },
+    RPush {
+        key: Bytes,
+        values: Vec<Bytes>,
+    },
+    LPop {
+        key: Bytes,
+    },
+    RPop {
+        key: Bytes,
+    },
+    LLen {
+        key: Bytes,
+    },
+    LIndex {
+        key: Bytes,
+        index: i64,
+    },
+    LRange {
+        key: Bytes,
+        start: i64,
+        stop: i64,
+    },
+    LTrim {
+        key: Bytes,
+        start: i64,
+        stop: i64,
+    },
+    HSet {
+        key: Bytes,
+        fields: Vec<(Bytes, Bytes)>,
+    },
+    HGet {
+        key: Bytes,
+        field: Bytes,
+    },
+    HDel {
+        key: Bytes,
+        fields: Vec<Bytes>,
+    },
+    HExists {
+        key: Bytes,
+        field: Bytes,
+    },
+    HLen {
+        key: Bytes,
+    },
+    HGetAll {
+        key: Bytes,
+    },
+    HScan {
+        key: Bytes,
+        cursor: u64,
+        pattern: Option<Bytes>,
+        count: usize,
+    },
+    SAdd {
+        key: Bytes,
+        members: Vec<Bytes>,
+    },
+    SRem {
+        key: Bytes,
+        members: Vec<Bytes>,
+    },
+    SIsMember {
+        key: Bytes,
+        member: Bytes,
+    },
+    SMembers {
+        key: Bytes,
+    },
+    SCard {
+        key: Bytes,
+    },
+    SUnion {
+        keys: Vec<Bytes>,
+    },
+    SInter {
+        keys: Vec<Bytes>,
+    },
+    SDiff {
+        keys: Vec<Bytes>,
+    },
+    SUnionStore {
+        destination: Bytes,
+        keys: Vec<Bytes>,
+    },
+    SInterStore {
+        destination: Bytes,
+        keys: Vec<Bytes>,
+    },
+    SDiffStore {
+        destination: Bytes,
+        keys: Vec<Bytes>,
+    },
+    SScan {
+        key: Bytes,
+        cursor: u64,
+        pattern: Option<Bytes>,
+        count: usize,
+    },
+    ZAdd {
+        key: Bytes,
+        members: Vec<(f64, Bytes)>,
+    },
+    ZRem {
+        key: Bytes,
+        members: Vec<Bytes>,
+    },
+    ZScore {
+        key: Bytes,
+        member: Bytes,
+    },
+    ZCard {
+        key: Bytes,
+    },
+    ZCount {
+        key: Bytes,
+        min: ScoreBound,
+        max: ScoreBound,
+    },
+    ZRank {
+        key: Bytes,
+        member: Bytes,
+    },
+    ZRevRank {
+        key: Bytes,
+        member: Bytes,
+    },
+    ZRange {
+        key: Bytes,
+        start: i64,
+        stop: i64,
+        with_scores: bool,
+    },
+    ZRevRange {
+        key: Bytes,
+        start: i64,
+        stop: i64,
+        with_scores: bool,
+    },
+    ZRangeByScore {
+        key: Bytes,
+        min: ScoreBound,
+        max: ScoreBound,
+        with_scores: bool,
+        limit: Option<(i64, i64)>,
+    },
+    ZRevRangeByScore {
+        key: Bytes,
+        max: ScoreBound,
+        min: ScoreBound,
+        with_scores: bool,
+        limit: Option<(i64, i64)>,
+    },
+    ZScan {
+        key: Bytes,
+        cursor: u64,
+        pattern: Option<Bytes>,
+        count: usize,
+    },
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum CommandError {
+    #[error("empty request")]
+    EmptyRequest,
+    #[error("unknown command '{name}'")]
+    UnknownCommand { name: String },
+    #[error("wrong number of arguments for '{name}' command")]
+    WrongArity { name: &'static str },
+    #[error("value '{argument}' is not an integer or out of range")]
+    NotAnInteger { argument: String },
+    #[error("value '{argument}' is not a valid float")]
+    NotAFloat { argument: String },
+    #[error("value is not an integer or out of range")]
+    ValueNotAnInteger,
+    #[error("value is not a valid float")]
+    ValueNotAFloat,
+    #[error("increment or decrement would overflow")]
+    Overflow,
+    #[error("increment would produce NaN or infinity")]
+    NotFinite,
+    #[error("invalid cursor '{argument}'")]
+    InvalidCursor { argument: String },
+    #[error("invalid expire time in '{name}' command")]
+    InvalidExpireTime { name: &'static str },
+    #[error("syntax error in '{name}' command")]
+    SyntaxError { name: &'static str },
+    #[error("writes are not allowed on a read-only replica")]
+    ReadOnly,
+}
+
+impl From<CommandError> for Response {
+    fn from(error: CommandError) -> Self {
+        Response::error(ResponseStatusCode::Err, &error.to_string())
+    }
+}
+
+impl From<WrongType> for Response {
+    fn from(error: WrongType) -> Self {
+        Response::error(ResponseStatusCode::WrongType, &error.to_string())
+    }
+}
+
+impl From<SnapshotError> for Response {
+    fn from(error: SnapshotError) -> Self {
+        match error {
+            SnapshotError::InProgress => {
+                Response::error(ResponseStatusCode::Busy, &error.to_string())
+            }
+            error => Response::error(ResponseStatusCode::Err, &error.to_string()),
+        }
+    }
+}
+
+impl From<AofError> for Response {
+    fn from(error: AofError) -> Self {
+        match error {
+            AofError::RewriteInProgress => {
+                Response::error(ResponseStatusCode::Busy, &error.to_string())
+            }
+            error => Response::error(ResponseStatusCode::Err, &error.to_string()),
+        }
+    }
+}
+
+struct Parser {
+    name: &'static str,
+    args: std::vec::IntoIter<Bytes>,
+}
+
+impl Parser {
+    fn new(name: &'static str, args: std::vec::IntoIter<Bytes>) -> Self {
+        Parser { name, args }
+    }
+
+    fn next_string(&mut self) -> Result<Bytes, CommandError> {
+        self.args
+            .next()
+            .ok_or(CommandError::WrongArity { name: self.name })
+    }
+
+    fn next_int(&mut self) -> Result<i64, CommandError> {
+        let argument = self.next_string()?;
+        std::str::from_utf8(&argument)
+            .ok()
+            .and_then(|argument| argument.parse().ok())
+            .ok_or_else(|| CommandError::NotAnInteger {
+                argument: String::from_utf8_lossy(&argument).into_owned(),
+            })
+    }
+
+    fn next_float(&mut self) -> Result<f64, CommandError> {
+        let argument = self.next_string()?;
+        parse_float(&argument).ok_or_else(|| CommandError::NotAFloat {
+            argument: String::from_utf8_lossy(&argument).into_owned(),
+        })
+    }
+
+    fn next_score_bound(&mut self) -> Result<ScoreBound, CommandError> {
+        let argument = self.next_string()?;
+        let bound = match argument.strip_prefix(b"(") {
+            Some(exclusive) => parse_float(exclusive).map(ScoreBound::Exclusive),
+            None => parse_float(&argument).map(ScoreBound::Inclusive),
+        };
+        bound.ok_or_else(|| CommandError::NotAFloat {
+            argument: String::from_utf8_lossy(&argument).into_owned(),
+        })
+    }
+
+    fn next_cursor(&mut self) -> Result<u64, CommandError> {
+        let argument = self.next_string()?;
+        std::str::from_utf8(&argument)
+            .ok()
+            .and_then(|argument| argument.parse().ok())
+            .ok_or_else(|| CommandError::InvalidCursor {
+                argument: String::from_utf8_lossy(&argument).into_owned(),
+            })
+    }
+
+    fn next_ttl(&mut self, unit: fn(u64) -> Duration) -> Result<Duration, CommandError> {
+        let ttl = self.next_int()?;
+        u64::try_from(ttl)
+            .ok()
+            .filter(|ttl| *ttl > 0)
+            .map(unit)
+            .ok_or(CommandError::InvalidExpireTime { name: self.name })
+    }
+
+    fn next_optional(&mut self) -> Option<Bytes> {
+        self.args.next()
+    }
+
+    fn has_remaining(&self) -> bool {
+        self.args.len() > 0
+    }
+
+    fn remaining(&mut self) -> Result<Vec<Bytes>, CommandError> {
+        let remaining: Vec<_> = self.args.by_ref().collect();
+        if remaining.is_empty() {
+            return Err(CommandError::WrongArity { name: self.name });
+        }
+        Ok(remaining)
+    }
+
+    fn syntax_error(&self) -> CommandError {
+        CommandError::SyntaxError { name: self.name }
+    }
+
+    fn finish(mut self) -> Result<(), CommandError> {
+        match self.args.next() {
+            Some(_) => Err(CommandError::WrongArity { name: self.name }),
+            None => Ok(()),
+        }
+    }
+}
+
+fn parse_float(argument: &[u8]) -> Option<f64> {
+    std::str::from_utf8(argument)
+        .ok()
+        .and_then(|argument| argument.parse::<f64>().ok())
+        .filter(|float| !float.is_nan())
+}
+
+fn parse(
+    name: &'static str,
+    args: std::vec::IntoIter<Bytes>,
+    f: impl FnOnce(&mut Parser) -> Result<Command, CommandError>,
+) -> Result<Command, CommandError> {
+    let mut parser = Parser::new(name, args);
+    let command = f(&mut parser)?;
+    parser.finish()?;
+    Ok(command)
+}
+
+impl TryFrom<Request> for Command {
+    type Error = CommandError;
+
+    fn try_from(request: Request) -> Result<Self, Self::Error> {
+        let mut args = request.strings.into_iter();
+        let name = args.next().ok_or(CommandError::EmptyRequest)?;
+        match name.to_ascii_uppercase().as_slice() {
+            b"GET" => parse("get", args, |p| {
+                Ok(Command::Get {
+                    key: p.next_string()?,
+                })
+            }),
+            b"SET" => parse("set", args, |p| {
+                let key = p.next_string()?;
+                let value = p.next_string()?;
+                let mut ttl = None;
+                while let Some(option) = p.next_optional() {
+                    ttl = match option.to_ascii_uppercase().as_slice() {
+                        b"EX" if ttl.is_none() => Some(p.next_ttl(Duration::from_secs)?),
+                        b"PX" if ttl.is_none() => Some(p.next_ttl(Duration::from_millis)?),
+                        _ => return Err(p.syntax_error()),
+                    };
+                }
+                Ok(Command::Set { key, value, ttl })
+            }),
+            b"DEL" => parse("del", args, |p| {
+                Ok(Command::Del {
+                    keys: p.remaining()?,
+                })
+            }),
+            b"INCR" => parse("incr", args, |p| {
+                Ok(Command::IncrBy {
+                    key: p.next_string()?,
+                    increment: 1,
+                })
+            }),
+            b"DECR" => parse("decr", args, |p| {
+                Ok(Command::IncrBy {
+                    key: p.next_string()?,
+                    increment: -1,
+                })
+            }),
+            b"INCRBY" => parse("incrby", args, |p| {
+                Ok(Command::IncrBy {
+                    key: p.next_string()?,
+                    increment: p.next_int()?,
+                })
+            }),
+            b"INCRBYFLOAT" => parse("incrbyfloat", args, |p| {
+                Ok(Command::IncrByFloat {
+                    key: p.next_string()?,
+                    increment: p.next_float()?,
+                })
+            }),
+            b"EXPIRE" => parse("expire", args, |p| {
+                Ok(Command::Expire {
+                    key: p.next_string()?,
+                    seconds: p.next_int()?,
+                })
+            }),
+            b"PEXPIRE" => parse("pexpire", args, |p| {
+                Ok(Command::PExpire {
+                    key: p.next_string()?,
+                    milliseconds: p.next_int()?,
+                })
+            }),
+            b"PEXPIREAT" => parse("pexpireat", args, |p| {
+                Ok(Command::PExpireAt {
+                    key: p.next_string()?,
+                    timestamp: p.next_int()?,
+                })
+            }),
+            b"TTL" => parse("ttl", args, |p| {
+                Ok(Command::Ttl {
+                    key: p.next_string()?,
+                })
+            }),
+            b"PTTL" => parse("pttl", args, |p| {
+                Ok(Command::PTtl {
+                    key: p.next_string()?,
+                })
+            }),
+            b"PERSIST" => parse("persist", args, |p| {
+                Ok(Command::Persist {
+                    key: p.next_string()?,
+                })
+            }),
+            b"LPUSH" => parse("lpush", args, |p| {
+                Ok(Command::LPush {
+                    key: p.next_string()?,
+                    values: p.remaining()?,
+                })
+            }),
+            b"RPUSH" => parse("rpush", args, |p| {
+                Ok(Command::RPush {
+                    key: p.next_string()?,
+                    values: p.remaining()?,
+                })
+            }),
+            b"LPOP" => parse("lpop", args, |p| {
+                Ok(Command::LPop {
+                    key: p.next_string()?,
+                })
+            }),
+            b"RPOP" => parse("rpop", args, |p| {
+                Ok(Command::RPop {
+                    key: p.next_string()?,
+                })
+            }),
+            b"LLEN" => parse("llen", args, |p| {
+                Ok(Command::LLen {
+                    key: p.next_string()?,
+                })
+            }),
+            b"LINDEX" => parse("lindex", args, |p| {
+                Ok(Command::LIndex {
+                    key: p.next_string()?,
+                    index: p.next_int()?,
+                })
+            }),
+            b"LRANGE" => parse("lrange", args, |p| {
+                Ok(Command::LRange {
+                    key: p.next_string()?,
+                    start: p.next_int()?,
+                    stop: p.next_int()?,
+                })
+            }),
+            b"LTRIM" => parse("ltrim", args, |p| {
+                Ok(Command::LTrim {
+                    key: p.next_string()?,
+                    start: p.next_int()?,
+                    stop: p.next_int()?,
+                })
+            }),
+            b"HSET" => parse("hset", args, |p| {
+                let key = p.next_string()?;
+                let mut fields = Vec::new();
+                loop {
+                    fields.push((p.next_string()?, p.next_string()?));
+                    if !p.has_remaining() {
+                        break;
+                    }
+                }
+                Ok(Command::HSet { key, fields })
+            }),
+            b"HGET" => parse("hget", args, |p| {
+                Ok(Command::HGet {
+                    key: p.next_string()?,
+                    field: p.next_string()?,
+                })
+            }),
+            b"HDEL" => parse("hdel", args, |p| {
+                Ok(Command::HDel {
+                    key: p.next_string()?,
+                    fields: p.remaining()?,
+                })
+            }),
+            b"HEXISTS" => parse("hexists", args, |p| {
+                Ok(Command::HExists {
+                    key: p.next_string()?,
+                    field: p.next_string()?,
+                })
+            }),
+            b"HLEN" => parse("hlen", args, |p| {
+                Ok(Command::HLen {
+                    key: p.next_string()?,
+                })
+            }),
+            b"HGETALL" => parse("hgetall", args, |p| {
+                Ok(Command::HGetAll {
+                    key: p.next_string()?,
+                })
+            }),
+            b"SADD" => parse("sadd", args, |p| {
+                Ok(Command::SAdd {
+                    key: p.next_string()?,
+                    members: p.remaining()?,
+                })
+            }),
+            b"SREM" => parse("srem", args, |p| {
+                Ok(Command::SRem {
+                    key: p.next_string()?,
+                    members: p.remaining()?,
+                })
+            }),
+            b"SISMEMBER" => parse("sismember", args, |p| {
+                Ok(Command::SIsMember {
+                    key: p.next_string()?,
+                    member: p.next_string()?,
+                })
+            }),
+            b"SMEMBERS" => parse("smembers", args, |p| {
+                Ok(Command::SMembers {
+                    key: p.next_string()?,
+                })
+            }),
+            b"SCARD" => parse("scard", args, |p| {
+                Ok(Command::SCard {
+                    key: p.next_string()?,
+                })
+            }),
+            b"SUNION" => parse("sunion", args, |p| {
+                Ok(Command::SUnion {
+                    keys: p.remaining()?,
+                })
+            }),
+            b"SINTER" => parse("sinter", args, |p| {
+                Ok(Command::SInter {
+                    keys: p.remaining()?,
+                })
+            }),
+            b"SDIFF" => parse("sdiff", args, |p| {
+                Ok(Command::SDiff {
+                    keys: p.remaining()?,
+                })
+            }),
+            b"SUNIONSTORE" => parse("sunionstore", args, |p| {
+                Ok(Command::SUnionStore {
+                    destination: p.next_string()?,
+                    keys: p.remaining()?,
+                })
+            }),
+            b"SINTERSTORE" => parse("sinterstore", args, |p| {
+                Ok(Command::SInterStore {
+                    destination: p.next_string()?,
+                    keys: p.remaining()?,
+                })
+            }),
+            b"SDIFFSTORE" => parse("sdiffstore", args, |p| {
+                Ok(Command::SDiffStore {
+                    destination: p.next_string()?,
+                    keys: p.remaining()?,
+                })
+            }),
+            b"SAVE" => parse("save", args, |_| Ok(Command::Save)),
+            b"BGSAVE" => parse("bgsave", args, |_| Ok(Command::BgSave)),
+            b"BGREWRITEAOF" => parse("bgrewriteaof", args, |_| Ok(Command::BgRewriteAof)),
+            b"ROLE" => parse("role", args, |_| Ok(Command::Role)),
+            b"SCAN" => parse("scan", args, |p| {
+                let cursor = p.next_cursor()?;
+                let (pattern, count) = parse_scan_options(p)?;
+                Ok(Command::Scan {
+                    cursor,
+                    pattern,
+                    count,
+                })
+            }),
+            b"HSCAN" => parse("hscan", args, |p| {
+                let key = p.next_string()?;
+                let cursor = p.next_cursor()?;
+                let (pattern, count) = parse_scan_options(p)?;
+                Ok(Command::HScan {
+                    key,
+                    cursor,
+                    pattern,
+                    count,
+                })
+            }),
+            b"SSCAN" => parse("sscan", args, |p| {
+                let key = p.next_string()?;
+                let cursor = p.next_cursor()?;
+                let (pattern, count) = parse_scan_options(p)?;
+                Ok(Command::SScan {
+                    key,
+                    cursor,
+                    pattern,
+                    count,
+                })
+            }),
+            b"ZSCAN" => parse("zscan", args, |p| {
+                let key = p.next_string()?;
+                let cursor = p.next_cursor()?;
+                let (pattern, count) = parse_scan_options(p)?;
+                Ok(Command::ZScan {
+                    key,
+                    cursor,
+                    pattern,
+                    count,
+                })
+            }),
+            b"ZADD" => parse("zadd", args, |p| {
+                let key = p.next_string()?;
+                let mut members = Vec::new();
+                loop {
+                    members.push((p.next_float()?, p.next_string()?));
+                    if !p.has_remaining() {
+                        break;
+                    }
+                }
+                Ok(Command::ZAdd { key, members })
+            }),
+            b"ZREM" => parse("zrem", args, |p| {
+                Ok(Command::ZRem {
+                    key: p.next_string()?,
+                    members: p.remaining()?,
+                })
+            }),
+            b"ZSCORE" => parse("zscore", args, |p| {
+                Ok(Command::ZScore {
+                    key: p.next_string()?,
+                    member: p.next_string()?,
+                })
+            }),
+            b"ZCARD" => parse("zcard", args, |p| {
+                Ok(Command::ZCard {
+                    key: p.next_string()?,
+                })
+            }),
+            b"ZCOUNT" => parse("zcount", args, |p| {
+                Ok(Command::ZCount {
+                    key: p.next_string()?,
+                    min: p.next_score_bound()?,
+                    max: p.next_score_bound()?,
+                })
+            }),
+            b"ZRANK" => parse("zrank", args, |p| {
+                Ok(Command::ZRank {
+                    key: p.next_string()?,
+                    member: p.next_string()?,
+                })
+            }),
+            b"ZREVRANK" => parse("zrevrank", args, |p| {
+                Ok(Command::ZRevRank {
+                    key: p.next_string()?,
+                    member: p.next_string()?,
+                })
+            }),
+            b"ZRANGE" => parse("zrange", args, |p| {
+                let key = p.next_string()?;
+                let start = p.next_int()?;
+                let stop = p.next_int()?;
+                let (with_scores, _) = parse_range_options(p, false)?;
+                Ok(Command::ZRange {
+                    key,
+                    start,
+                    stop,
+                    with_scores,
+                })
+            }),
+            b"ZREVRANGE" => parse("zrevrange", args, |p| {
+                let key = p.next_string()?;
+                let start = p.next_int()?;
+                let stop = p.next_int()?;
+                let (with_scores, _) = parse_range_options(p, false)?;
+                Ok(Command::ZRevRange {
+                    key,
+                    start,
+                    stop,
+                    with_scores,
+                })
+            }),
+            b"ZRANGEBYSCORE" => parse("zrangebyscore", args, |p| {
+                let key = p.next_string()?;
+                let min = p.next_score_bound()?;
+                let max = p.next_score_bound()?;
+                let (with_scores, limit) = parse_range_options(p, true)?;
+                Ok(Command::ZRangeByScore {
+                    key,
+                    min,
+                    max,
+                    with_scores,
+                    limit,
+                })
+            }),
+            b"ZREVRANGEBYSCORE" => parse("zrevrangebyscore", args, |p| {
+                let key = p.next_string()?;
+                let max = p.next_score_bound()?;
+                let min = p.next_score_bound()?;
+                let (with_scores, limit) = parse_range_options(p, true)?;
+                Ok(Command::ZRevRangeByScore {
+                    key,
+                    max,
+                    min,
+                    with_scores,
+                    limit,
+                })
+            }),
+            _ => Err(CommandError::UnknownCommand {
+                name: String::from_utf8_lossy(&name).into_owned(),
+            }),
+        }
+    }
+}
+
+fn parse_range_options(
+    p: &mut Parser,
+    allow_limit: bool,
+) -> Result<(bool, Option<(i64, i64)>), CommandError> {
+    let mut with_scores = false;
+    let mut limit = None;
+    while let Some(option) = p.next_optional() {
+        match option.to_ascii_uppercase().as_slice() {
+            b"WITHSCORES" => with_scores = true,
+            b"LIMIT" if allow_limit => limit = Some((p.next_int()?, p.next_int()?)),
+            _ => return Err(p.syntax_error()),
+        }
+    }
+    Ok((with_scores, limit))
+}
+
+fn parse_scan_options(p: &mut Parser) -> Result<(Option<Bytes>, usize), CommandError> {
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    while let Some(option) = p.next_optional() {
+        match option.to_ascii_uppercase().as_slice() {
+            b"MATCH" => pattern = Some(p.next_string()?),
+            b"COUNT" => {
+                count = usize::try_from(p.next_int()?)
+                    .ok()
+                    .filter(|count| *count > 0)
+                    .ok_or(p.syntax_error())?
+            }
+            _ => return Err(p.syntax_error()),
+        }
+    }
+    Ok((pattern, count))
+}
+
+enum Propagation {
+    Verbatim,
+    SetWithExpiry { key: Bytes, value: Bytes },
+    Expiry { key: Bytes },
+}
+
+impl Command {
+    pub fn is_write(&self) -> bool {
+        self.propagation().is_some()
+    }
+
+    fn propagation(&self) -> Option<Propagation> {
+        let propagation = match self {
+            Command::Set {
+                key,
+                value,
+                ttl: Some(_),
+            } => Propagation::SetWithExpiry {
+                key: key.clone(),
+                value: value.clone(),
+            },
+            Command::Expire { key, .. } | Command::PExpire { key, .. } => {
+                Propagation::Expiry { key: key.clone() }
+            }
+            Command::Set { .. }
+            | Command::Del { .. }
+            | Command::IncrBy { .. }
+            | Command::IncrByFloat { .. }
+            | Command::PExpireAt { .. }
+            | Command::Persist { .. }
+            | Command::LPush { .. }
+            | Command::RPush { .. }
+            | Command::LPop { .. }
+            | Command::RPop { .. }
+            | Command::LTrim { .. }
+            | Command::HSet { .. }
+            | Command::HDel { .. }
+            | Command::SAdd { .. }
+            | Command::SRem { .. }
+            | Command::SUnionStore { .. }
+            | Command::SInterStore { .. }
+            | Command::SDiffStore { .. }
+            | Command::ZAdd { .. }
+            | Command::ZRem { .. } => Propagation::Verbatim,
+            _ => return None,
+        };
+        Some(propagation)
+    }
+
+    fn execute_in(self, keyspace: &mut Keyspace) -> Result<Response, WrongType> {
+        let response = match self {
+            Command::Get { key } => match keyspace.get::<Bytes>(&key)? {
+                Some(value) => Response::ok(Value::String(value.clone())),
+                None => Response::nx(),
+            },
+            Command::Set { key, value, ttl } => {
+                keyspace.set(key, Object::String(value), ttl);
+                Response::ok(Value::String(Bytes::from_static(b"OK")))
+            }
+            Command::Del { keys } => match keyspace.del(&keys) {
+                0 => not_found(Value::Int(0)),
+                deleted => Response::ok(Value::Int(deleted as i64)),
+            },
+            Command::IncrBy { key, increment } => increment_by(keyspace, key, |current| {
+                let current = match current {
+                    Some(current) => parse_value(current).ok_or(CommandError::ValueNotAnInteger)?,
+                    None => 0,
+                };
+                let updated = current
+                    .checked_add(increment)
+                    .ok_or(CommandError::Overflow)?;
+                Ok((Bytes::from(updated.to_string()), Value::Int(updated)))
+            })?,
+            Command::IncrByFloat { key, increment } => increment_by(keyspace, key, |current| {
+                let current = match current {
+                    Some(current) => parse_float(current).ok_or(CommandError::ValueNotAFloat)?,
+                    None => 0.0,
+                };
+                let updated = current + increment;
+                if !updated.is_finite() {
+                    return Err(CommandError::NotFinite);
+                }
+                Ok((Bytes::from(updated.to_string()), Value::Double(updated)))
+            })?,
+            Command::Expire { key, seconds } => {
+                expire(keyspace, &key, seconds.saturating_mul(1000))
+            }
+            Command::PExpire { key, milliseconds } => expire(keyspace, &key, milliseconds),
+            Command::PExpireAt { key, timestamp } => {
+                let when = UNIX_EPOCH + Duration::from_millis(timestamp.max(0) as u64);
+                match keyspace.expire_at(&key, when) {
+                    true => Response::ok(Value::Int(1)),
+                    false => not_found(Value::Int(0)),
+                }
+            }
+            Command::Ttl { key } => {
+                ttl(keyspace, &key, |ttl| (ttl.as_millis() as i64 + 500) / 1000)
+            }
+            Command::PTtl { key } => ttl(keyspace, &key, |ttl| ttl.as_millis() as i64),
+            Command::Persist { key } => match keyspace.persist(&key) {
+                Some(persisted) => Response::ok(Value::Int(persisted as i64)),
+                None => not_found(Value::Int(0)),
+            },
+            Command::LPush { key, values } => {
+                let len = keyspace.upsert(&key, |list: &mut VecDeque<Bytes>| {
+                    for value in values {
+                        list.push_front(value);
+                    }
+                    list.len()
+                })?;
+                Response::ok(Value::Int(len as i64))
+            }
+            Command::RPush { key, values } => {
+                let len = keyspace.upsert(&key, |list: &mut VecDeque<Bytes>| {
+                    list.extend(values);
+                    list.len()
+                })?;
+                Response::ok(Value::Int(len as i64))
+            }
+            Command::LPop { key } => {
+                match keyspace.update(&key, |list: &mut VecDeque<Bytes>| list.pop_front())? {
+                    Some(Some(value)) => Response::ok(Value::String(value)),
+                    _ => Response::nx(),
+                }
+            }
+            Command::RPop { key } => {
+                match keyspace.update(&key, |list: &mut VecDeque<Bytes>| list.pop_back())? {
+                    Some(Some(value)) => Response::ok(Value::String(value)),
+                    _ => Response::nx(),
+                }
+            }
+            Command::LLen { key } => match keyspace.get::<VecDeque<Bytes>>(&key)? {
+                Some(list) => Response::ok(Value::Int(list.len() as i64)),
+                None => not_found(Value::Int(0)),
+            },
+            Command::LIndex { key, index } => {
+                let value = keyspace.get::<VecDeque<Bytes>>(&key)?.and_then(|list| {
+                    let index = if index < 0 {
+                        index.checked_add(list.len() as i64)?
+                    } else {
+                        index
+                    };
+                    list.get(usize::try_from(index).ok()?).cloned()
+                });
+                match value {
+                    Some(value) => Response::ok(Value::String(value)),
+                    None => Response::nx(),
+                }
+            }
+            Command::LRange { key, start, stop } => match keyspace.get::<VecDeque<Bytes>>(&key)? {
+                Some(list) => {
+                    let (start, end) = normalize_range(start, stop, list.len());
+                    let values = list.range(start..end).cloned().map(Value::String);
+                    Response::ok(Value::Array(values.collect()))
+                }
+                None => not_found(Value::Array(Vec::new())),
+            },
+            Command::LTrim { key, start, stop } => {
+                let trimmed = keyspace.update(&key, |list: &mut VecDeque<Bytes>| {
+                    let (start, end) = normalize_range(start, stop, list.len());
+                    list.truncate(end);
+                    list.drain(..start);
+                })?;
+                match trimmed {
+                    Some(()) => Response::ok(Value::String(Bytes::from_static(b"OK"))),
+                    None => not_found(Value::String(Bytes::from_static(b"OK"))),
+                }
+            }
+            Command::HSet { key, fields } => {
+                let added = keyspace.upsert(&key, |hash: &mut Dict<Bytes, Bytes>| {
+                    fields
+                        .into_iter()
+                        .filter(|(field, value)| {
+                            hash.insert(field.clone(), value.clone()).is_none()
+                        })
+                        .count()
+                })?;
+                Response::ok(Value::Int(added as i64))
+            }
+            Command::HGet { key, field } => {
+                match keyspace
+                    .get::<Dict<Bytes, Bytes>>(&key)?
+                    .and_then(|hash| hash.get(&field))
+                {
+                    Some(value) => Response::ok(Value::String(value.clone())),
+                    None => Response::nx(),
+                }
+            }
+            Command::HDel { key, fields } => {
+                let removed = keyspace.update(&key, |hash: &mut Dict<Bytes, Bytes>| {
+                    fields
+                        .iter()
+                        .filter(|field| hash.remove(*field).is_some())
+                        .count()
+                })?;
+                match removed {
+                    Some(removed) => Response::ok(Value::Int(removed as i64)),
+                    None => not_found(Value::Int(0)),
+                }
+            }
+            Command::HExists { key, field } => {
+                match keyspace
+                    .get::<Dict<Bytes, Bytes>>(&key)?
+                    .map(|hash| hash.contains_key(&field))
+                {
+                    Some(exists) => Response::ok(Value::Int(exists as i64)),
+                    None => not_found(Value::Int(0)),
+                }
+            }
+            Command::HLen { key } => match keyspace.get::<Dict<Bytes, Bytes>>(&key)? {
+                Some(hash) => Response::ok(Value::Int(hash.len() as i64)),
+                None => not_found(Value::Int(0)),
+            },
+            Command::HGetAll { key } => match keyspace.get::<Dict<Bytes, Bytes>>(&key)? {
+                Some(hash) => {
+                    let pairs = hash.iter().map(|(field, value)| {
+                        Value::Array(vec![
+                            Value::String(field.clone()),
+                            Value::String(value.clone()),
+                        ])
+                    });
+                    Response::ok(Value::Array(pairs.collect()))
+                }
+                None => not_found(Value::Array(Vec::new())),
+            },
+            Command::SAdd { key, members } => {
+                let added = keyspace.upsert(&key, |set: &mut Set| {
+                    members
+                        .into_iter()
+                        .filter(|member| set.insert(member.clone()))
+                        .count()
+                })?;
+                Response::ok(Value::Int(added as i64))
+            }
+            Command::SRem { key, members } => {
+                let removed = keyspace.update(&key, |set: &mut Set| {
+                    members.iter().filter(|member| set.remove(member)).count()
+                })?;
+                match removed {
+                    Some(removed) => Response::ok(Value::Int(removed as i64)),
+                    None => not_found(Value::Int(0)),
+                }
+            }
+            Command::SIsMember { key, member } => match keyspace.get::<Set>(&key)? {
+                Some(set) => Response::ok(Value::Int(set.contains(&member) as i64)),
+                None => not_found(Value::Int(0)),
+            },
+            Command::SMembers { key } => match keyspace.get::<Set>(&key)? {
+                Some(set) => Response::ok(set_members(set)),
+                None => not_found(Value::Array(Vec::new())),
+            },
+            Command::SCard { key } => match keyspace.get::<Set>(&key)? {
+                Some(set) => Response::ok(Value::Int(set.len() as i64)),
+                None => not_found(Value::Int(0)),
+            },
+            Command::SUnion { keys } => Response::ok(set_members(&combine_sets(
+                keyspace,
+                &keys,
+                SetOperation::Union,
+            )?)),
+            Command::SInter { keys } => Response::ok(set_members(&combine_sets(
+                keyspace,
+                &keys,
+                SetOperation::Inter,
+            )?)),
+            Command::SDiff { keys } => Response::ok(set_members(&combine_sets(
+                keyspace,
+                &keys,
+                SetOperation::Diff,
+            )?)),
+            Command::SUnionStore { destination, keys } => {
+                store_set(keyspace, destination, &keys, SetOperation::Union)?
+            }
+            Command::SInterStore { destination, keys } => {
+                store_set(keyspace, destination, &keys, SetOperation::Inter)?
+            }
+            Command::SDiffStore { destination, keys } => {
+                store_set(keyspace, destination, &keys, SetOperation::Diff)?
+            }
+            Command::Save => match keyspace.save() {
+                Ok(()) => Response::ok(Value::String(Bytes::from_static(b"OK"))),
+                Err(e) => e.into(),
+            },
+            Command::BgSave => match keyspace.save_in_background() {
+                Ok(_) => Response::ok(Value::String(Bytes::from_static(
+                    b"Background saving started",
+                ))),
+                Err(e) => e.into(),
+            },
+            Command::Role => Response::ok(keyspace.replication().role().into()),
+            Command::BgRewriteAof => match keyspace.rewrite_append_only_file() {
+                Ok(_) => Response::ok(Value::String(Bytes::from_static(
+                    b"Background append only file rewriting started",
+                ))),
+                Err(e) => e.into(),
+            },
+            Command::Scan {
+                cursor,
+                pattern,
+                count,
+            } => {
+                let (cursor, keys) = keyspace.scan(cursor, count);
+                let keys = keys
+                    .into_iter()
+                    .filter(|key| matches_pattern(&pattern, key))
+                    .map(Value::String);
+                Response::ok(scan_reply(cursor, keys.collect()))
+            }
+            Command::HScan {
+                key,
+                cursor,
+                pattern,
+                count,
+            } => match keyspace.get::<Dict<Bytes, Bytes>>(&key)? {
+                Some(hash) => {
+                    let mut pairs = Vec::new();
+                    let cursor = hash.scan_batch(cursor, count, |field, value| {
+                        if matches_pattern(&pattern, field) {
+                            pairs.push(Value::Array(vec![
+                                Value::String(field.clone()),
+                                Value::String(value.clone()),
+                            ]));
+                        }
+                    });
+                    Response::ok(scan_reply(cursor, pairs))
+                }
+                None => not_found(scan_reply(0, Vec::new())),
+            },
+            Command::SScan {
+                key,
+                cursor,
+                pattern,
+                count,
+            } => match keyspace.get::<Set>(&key)? {
+                Some(set) => {
+                    let mut members = Vec::new();
+                    let cursor = set.scan(cursor, count, |member| {
+                        if matches_pattern(&pattern, &member) {
+                            members.push(Value::String(member));
+                        }
+                    });
+                    Response::ok(scan_reply(cursor, members))
+                }
+                None => not_found(scan_reply(0, Vec::new())),
+            },
+            Command::ZScan {
+                key,
+                cursor,
+                pattern,
+                count,
+            } => match keyspace.get::<SortedSet>(&key)? {
+                Some(set) => {
+                    let mut pairs = Vec::new();
+                    let cursor = set.scan(cursor, count, |member, score| {
+                        if matches_pattern(&pattern, member) {
+                            pairs.push(Value::Array(vec![
+                                Value::String(member.clone()),
+                                Value::Double(score),
+                            ]));
+                        }
+                    });
+                    Response::ok(scan_reply(cursor, pairs))
+                }
+                None => not_found(scan_reply(0, Vec::new())),
+            },
+            Command::ZAdd { key, members } => {
+                let added = keyspace.upsert(&key, |set: &mut SortedSet| {
+                    members
+                        .into_iter()
+                        .filter(|(score, member)| set.insert(member.clone(), *score))
+                        .count()
+                })?;
+                Response::ok(Value::Int(added as i64))
+            }
+            Command::ZRem { key, members } => {
+                let removed = keyspace.update(&key, |set: &mut SortedSet| {
+                    members.iter().filter(|member| set.remove(member)).count()
+                })?;
+                match removed {
+                    Some(removed) => Response::ok(Value::Int(removed as i64)),
+                    None => not_found(Value::Int(0)),
+                }
+            }
+            Command::ZScore { key, member } => {
+                match keyspace
+                    .get::<SortedSet>(&key)?
+                    .and_then(|set| set.score(&member))
+                {
+                    Some(score) => Response::ok(Value::Double(score)),
+                    None => Response::nx(),
+                }
+            }
+            Command::ZCard { key } => match keyspace.get::<SortedSet>(&key)? {
+                Some(set) => Response::ok(Value::Int(set.len() as i64)),
+                None => not_found(Value::Int(0)),
+            },
+            Command::ZCount { key, min, max } => match keyspace.get::<SortedSet>(&key)? {
+                Some(set) => Response::ok(Value::Int(set.rank_range(min, max).len() as i64)),
+                None => not_found(Value::Int(0)),
+            },
+            Command::ZRank { key, member } => zrank(keyspace, &key, &member, false)?,
+            Command::ZRevRank { key, member } => zrank(keyspace, &key, &member, true)?,
+            Command::ZRange {
+                key,
+                start,
+                stop,
+                with_scores,
+            } => zrange(keyspace, &key, start, stop, with_scores, false)?,
+            Command::ZRevRange {
+                key,
+                start,
+                stop,
+                with_scores,
+            } => zrange(keyspace, &key, start, stop, with_scores, true)?,
+            Command::ZRangeByScore {
+                key,
+                min,
+                max,
+                with_scores,
+                limit,
+            } => zrange_by_score(keyspace, &key, min, max, with_scores, limit, false)?,
+            Command::ZRevRangeByScore {
+                key,
+                max,
+                min,
+                with_scores,
+                limit,
+            } => zrange_by_score(keyspace, &key, min, max, with_scores, limit, true)?,
+        };
+        Ok(response)
+    }
+}
+
+fn not_found(value: Value) -> Response {
+    Response {
+        status_code: ResponseStatusCode::Nx,
+        value,
+    }
+}
+
+fn increment_by(
+    keyspace: &mut Keyspace,
+    key: Bytes,
+    apply: impl FnOnce(Option<&Bytes>) -> Result<(Bytes, Value), CommandError>,
+) -> Result<Response, WrongType> {
+    let current = keyspace.get::<Bytes>(&key)?;
+    let exists = current.is_some();
+    let (updated, value) = match apply(current) {
+        Ok(result) => result,
+        Err(error) => return Ok(error.into()),
+    };
+    if exists {
+        keyspace.update(&key, |current: &mut Bytes| *current = updated)?;
+    } else {
+        keyspace.set(key, Object::String(updated), None);
+    }
+    Ok(Response::ok(value))
+}
+
+fn parse_value(value: &[u8]) -> Option<i64> {
+    std::str::from_utf8(value).ok()?.parse().ok()
+}
+
+fn expire(keyspace: &mut Keyspace, key: &Bytes, milliseconds: i64) -> Response {
+    let ttl = Duration::from_millis(milliseconds.max(0) as u64);
+    match keyspace.expire(key, ttl) {
+        true => Response::ok(Value::Int(1)),
+        false => not_found(Value::Int(0)),
+    }
+}
+
+fn ttl(keyspace: &mut Keyspace, key: &Bytes, convert: impl Fn(Duration) -> i64) -> Response {
+    match keyspace.ttl(key) {
+        Some(Some(ttl)) => Response::ok(Value::Int(convert(ttl))),
+        Some(None) => Response::ok(Value::Int(-1)),
+        None => not_found(Value::Int(-2)),
+    }
+}
+
+#[derive(Clone, Copy)]
+enum SetOperation {
+    Union,
+    Inter,
+    Diff,
+}
+
+fn combine_sets(
+    keyspace: &mut Keyspace,
+    keys: &[Bytes],
+    operation: SetOperation,
+) -> Result<Set, WrongType> {
+    let mut keys = keys.iter();
+    let first = keys.next().expect("parser requires at least one key");
+    let mut result = keyspace.get::<Set>(first)?.cloned().unwrap_or_default();
+    for key in keys {
+        let other = keyspace.get::<Set>(key)?;
+        match (operation, other) {
+            (SetOperation::Union, Some(other)) => {
+                for member in other.members() {
+                    result.insert(member);
+                }
+            }
+            (SetOperation::Inter, Some(other)) => result.retain(|member| other.contains(member)),
+            (SetOperation::Inter, None) => result = Set::new(),
+            (SetOperation::Diff, Some(other)) => result.retain(|member| !other.contains(member)),
+            (SetOperation::Union | SetOperation::Diff, None) => {}
+        }
+    }
+    Ok(result)
+}
+
+fn store_set(
+    keyspace: &mut Keyspace,
+    destination: Bytes,
+    keys: &[Bytes],
+    operation: SetOperation,
+) -> Result<Response, WrongType> {
+    let result = combine_sets(keyspace, keys, operation)?;
+    let len = result.len();
+    if result.is_empty() {
+        keyspace.del(&[destination]);
+    } else {
+        keyspace.set(destination, Object::Set(result), None);
+    }
+    Ok(Response::ok(Value::Int(len as i64)))
+}
+
+fn set_members(set: &Set) -> Value {
+    Value::Array(set.members().into_iter().map(Value::String).collect())
+}
+
+fn matches_pattern(pattern: &Option<Bytes>, string: &[u8]) -> bool {
+    pattern
+        .as_ref()
+        .is_none_or(|pattern| glob::matches(pattern, string))
+}
+
+fn scan_reply(cursor: u64, items: Vec<Value>) -> Value {
+    Value::Array(vec![Value::Int(cursor as i64), Value::Array(items)])
+}
+
+fn zrank(
+    keyspace: &mut Keyspace,
+    key: &Bytes,
+    member: &Bytes,
+    reverse: bool,
+) -> Result<Response, WrongType> {
+    let Some(set) = keyspace.get::<SortedSet>(key)? else {
+        return Ok(Response::nx());
+    };
+    let response = match set.rank(member) {
+        Some(rank) if reverse => Response::ok(Value::Int((set.len() - 1 - rank) as i64)),
+        Some(rank) => Response::ok(Value::Int(rank as i64)),
+        None => Response::nx(),
+    };
+    Ok(response)
+}
+
+fn zrange(
+    keyspace: &mut Keyspace,
+    key: &Bytes,
+    start: i64,
+    stop: i64,
+    with_scores: bool,
+    reverse: bool,
+) -> Result<Response, WrongType> {
+    let Some(set) = keyspace.get::<SortedSet>(key)? else {
+        return Ok(not_found(Value::Array(Vec::new())));
+    };
+    let (start, end) = normalize_range(start, stop, set.len());
+    let ranks = match reverse {
+        true => set.len() - end..set.len() - start,
+        false => start..end,
+    };
+    Ok(sorted_set_entries(set, ranks, with_scores, reverse))
+}
+
+fn zrange_by_score(
+    keyspace: &mut Keyspace,
+    key: &Bytes,
+    min: ScoreBound,
+    max: ScoreBound,
+    with_scores: bool,
+    limit: Option<(i64, i64)>,
+    reverse: bool,
+) -> Result<Response, WrongType> {
+    let Some(set) = keyspace.get::<SortedSet>(key)? else {
+        return Ok(not_found(Value::Array(Vec::new())));
+    };
+    let mut ranks = set.rank_range(min, max);
+    if let Some((offset, count)) = limit {
+        let offset = usize::try_from(offset).unwrap_or(usize::MAX);
+        let count = usize::try_from(count).unwrap_or(usize::MAX);
+        ranks = match reverse {
+            true => {
+                let end = ranks.end.saturating_sub(offset).max(ranks.start);
+                end.saturating_sub(count).max(ranks.start)..end
+            }
+            false => {
+                let start = ranks.start.saturating_add(offset).min(ranks.end);
+                start..start.saturating_add(count).min(ranks.end)
+            }
+        };
+    }
+    Ok(sorted_set_entries(set, ranks, with_scores, reverse))
+}
+
+fn sorted_set_entries(
+    set: &SortedSet,
+    ranks: Range<usize>,
+    with_scores: bool,
+    reverse: bool,
+) -> Response {
+    let mut entries = set.range(ranks);
+    if reverse {
+        entries.reverse();
+    }
+    let values = entries
+        .into_iter()
+        .map(|(member, score)| match with_scores {
+            true => Value::Array(vec![Value::String(member), Value::Double(score)]),
+            false => Value::String(member),
+        })
+        .collect();
+    Response::ok(Value::Array(values))
+}
+
+fn normalize_range(start: i64, end: i64, len: usize) -> (usize, usize) {
+    let len = len as i64;
+    let start = if start < 0 { len + start } else { start }.max(0);
+    let end = if end < 0 { len + end } else { end }.min(len - 1);
+    if start > end {
+        return (0, 0);
+    }
+    (start as usize, end as usize + 1)
+}
+
+impl Propagation {
+    fn into_requests(self, keyspace: &mut Keyspace, request: Request) -> Vec<Request> {
+        let key = match self {
+            Propagation::Verbatim => return vec![request],
+            Propagation::SetWithExpiry { key, value } => {
+                let set = request_from(&[b"SET", &key, &value]);
+                return vec![set, absolute_expiry(keyspace, key)];
+            }
+            Propagation::Expiry { key } => key,
+        };
+        vec![absolute_expiry(keyspace, key)]
+    }
+}
+
+fn absolute_expiry(keyspace: &mut Keyspace, key: Bytes) -> Request {
+    match keyspace.ttl(&key) {
+        Some(Some(ttl)) => {
+            let when = SystemTime::now() + ttl;
+            let timestamp = when.duration_since(UNIX_EPOCH).unwrap_or_default();
+            let timestamp = timestamp.as_millis().to_string();
+            request_from(&[b"PEXPIREAT", &key, timestamp.as_bytes()])
+        }
+        Some(None) => request_from(&[b"PERSIST", &key]),
+        None => request_from(&[b"DEL", &key]),
+    }
+}
+
+fn request_from(strings: &[&[u8]]) -> Request {
+    Request {
+        strings: strings
+            .iter()
+            .map(|string| Bytes::copy_from_slice(string))
+            .collect(),
+    }
+}
+
+pub fn execute(db: &Db, request: Request) -> Response {
+    let command = match Command::try_from(request.clone()) {
+        Ok(command) => command,
+        Err(e) => return e.into(),
+    };
+    if command.is_write() {
+        if db.replication().is_replica() {
+            return CommandError::ReadOnly.into();
+        }
+        if let Err(e) = db.check_writable() {
+            return e.into();
+        }
+    }
+    execute_command(db, command, request)
+}
+
+pub fn replicate(db: &Db, request: Request) -> Response {
+    match Command::try_from(request.clone()) {
+        Ok(command) => execute_command(db, command, request),
+        Err(e) => e.into(),
+    }
+}
+
+fn execute_command(db: &Db, command: Command, request: Request) -> Response {
+    let propagation = command.propagation();
+    let mut keyspace = db.lock();
+    let response = command
+        .execute_in(&mut keyspace)
+        .unwrap_or_else(Response::from);
+    if let Some(propagation) = propagation {
+        if response.status_code == ResponseStatusCode::Ok {
+            let requests = propagation.into_requests(&mut keyspace, request);
+            // The write is already applied and kept pending in the append-only
+            // file, so it still gets its real reply; later writes are refused
+            // until the pending bytes are written.
+            if let Err(e) = keyspace.propagate(&requests) {
+                log::error!("Failed to write to the append-only file: {:?}", e);
+            }
+        }
+    }
+    response
+}
+
+pub fn apply(keyspace: &mut Keyspace, request: Request) -> Response {
+    match Command::try_from(request) {
+        Ok(command) => command.execute_in(keyspace).unwrap_or_else(Response::from),
+        Err(e) => e.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aof::{AofConfig, AppendOnlyFile, FsyncPolicy};
+    use crate::db::Persistence;
+
+    fn request(strings: &[&str]) -> Request {
+        Request {
+            strings: strings
+                .iter()
+                .map(|string| Bytes::copy_from_slice(string.as_bytes()))
+                .collect(),
+        }
+    }
+
+    fn string(string: &str) -> Value {
+        Value::String(Bytes::copy_from_slice(string.as_bytes()))
+    }
+
+    #[test]
+    fn test_parsing_commands() {
+        assert_eq!(
+            Command::try_from(request(&["get", "key"])),
+            Ok(Command::Get {
+                key: Bytes::from_static(b"key")
+            })
+        );
+        assert_eq!(
+            Command::try_from(request(&["LRANGE", "key", "-3", "10"])),
+            Ok(Command::LRange {
+                key: Bytes::from_static(b"key"),
+                start: -3,
+                stop: 10,
+            })
+        );
+        assert_eq!(
+            Command::try_from(request(&["DEL", "a", "b"])),
+            Ok(Command::Del {
+                keys: vec![Bytes::from_static(b"a"), Bytes::from_static(b"b")]
+            })
+        );
+    }
+
+    #[test]
+    fn test_parsing_errors() {
+        assert_eq!(
+            Command::try_from(request(&[])),
+            Err(CommandError::EmptyRequest)
+        );
+        assert_eq!(
+            Command::try_from(request(&["NOPE"])),
+            Err(CommandError::UnknownCommand {
+                name: "NOPE".to_string()
+            })
+        );
+        for strings in [
+            &["GET"][..],
+            &["GET", "a", "b"],
+            &["SET", "key"],
+            &["DEL"],
+            &["LRANGE", "key", "0"],
+        ] {
+            assert!(matches!(
+                Command::try_from(request(strings)),
+                Err(CommandError::WrongArity { .. })
+            ));
+        }
+        assert_eq!(
+            Command::try_from(request(&["LRANGE", "key", "zero", "1"])),
+            Err(CommandError::NotAnInteger {
+                argument: "zero".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_set_then_get() {
+        let db = Db::new();
+        let response = execute(&db, request(&["SET", "key", "value"]));
+        assert_eq!(response, Response::ok(string("OK")));
+        let response = execute(&db, request(&["get", "key"]));
+        assert_eq!(response, Response::ok(string("value")));
+    }
+
+    #[test]
+    fn test_get_missing_key() {
+        let db = Db::new();
+        let response = execute(&db, request(&["GET", "key"]));
+        assert_eq!(response, Response::nx());
+    }
+
+    #[test]
+    fn test_del_counts_removed_keys() {
+        let db = Db::new();
+        execute(&db, request(&["SET", "a", "1"]));
+        execute(&db, request(&["SET", "b", "2"]));
+        let response = execute(&db, request(&["DEL", "a", "b", "c"]));
+        assert_eq!(response, Response::ok(Value::Int(2)));
+        let response = execute(&db, request(&["DEL", "a"]));
+        assert_eq!(response.status_code, ResponseStatusCode::Nx);
+        assert_eq!(execute(&db, request(&["GET", "b"])), Response::nx());
+    }
+
+    #[test]
+    fn test_errors_map_to_err_status() {
+        let db = Db::new();
+        let response = execute(&db, request(&["GET"]));
+        assert_eq!(
+            response,
+            Response::error(
+                ResponseStatusCode::Err,
+                "wrong number of arguments for 'get' command"
+            )
+        );
+        let response = execute(&db, request(&["NOPE"]));
+        assert_eq!(
+            response,
+            Response::error(ResponseStatusCode::Err, "unknown command 'NOPE'")
+        );
+    }
+
+    #[test]
+    fn test_binary_keys_and_values() {
+        let db = Db::new();
+        let key = Bytes::from_static(&[0xff, 0, b'k']);
+        let value = Bytes::from_static("truskawka \u{1f353}".as_bytes());
+        let response = execute(
+            &db,
+            Request {
+                strings: vec![Bytes::from_static(b"SET"), key.clone(), value.clone()],
+            },
+        );
+        assert_eq!(response.status_code, ResponseStatusCode::Ok);
+        let response = execute(
+            &db,
+            Request {
+                strings: vec![Bytes::from_static(b"GET"), key],
+            },
+        );
+        assert_eq!(response, Response::ok(Value::String(value)));
+    }
+
+    #[test]
+    fn test_parsing_set_options() {
+        assert_eq!(
+            Command::try_from(request(&["SET", "key", "value", "ex", "10"])),
+            Ok(Command::Set {
+                key: Bytes::from_static(b"key"),
+                value: Bytes::from_static(b"value"),
+                ttl: Some(Duration::from_secs(10)),
+            })
+        );
+        assert_eq!(
+            Command::try_from(request(&["SET", "key", "value", "PX", "10"])),
+            Ok(Command::Set {
+                key: Bytes::from_static(b"key"),
+                value: Bytes::from_static(b"value"),
+                ttl: Some(Duration::from_millis(10)),
+            })
+        );
+        assert_eq!(
+            Command::try_from(request(&["SET", "key", "value", "PX", "5000000000"])),
+            Ok(Command::Set {
+                key: Bytes::from_static(b"key"),
+                value: Bytes::from_static(b"value"),
+                ttl: Some(Duration::from_millis(5_000_000_000)),
+            })
+        );
+        for strings in [
+            &["SET", "key", "value", "EX", "0"][..],
+            &["SET", "key", "value", "PX", "-5"],
+        ] {
+            assert_eq!(
+                Command::try_from(request(strings)),
+                Err(CommandError::InvalidExpireTime { name: "set" })
+            );
+        }
+        for strings in [
+            &["SET", "key", "value", "EX", "1", "PX", "1"][..],
+            &["SET", "key", "value", "NOPE"],
+        ] {
+            assert_eq!(
+                Command::try_from(request(strings)),
+                Err(CommandError::SyntaxError { name: "set" })
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expire_ttl_and_persist() {
+        let db = Db::new();
+        assert_eq!(
+            execute(&db, request(&["EXPIRE", "key", "10"])),
+            not_found(Value::Int(0))
+        );
+        assert_eq!(
+            execute(&db, request(&["TTL", "key"])),
+            not_found(Value::Int(-2))
+        );
+        execute(&db, request(&["SET", "key", "value"]));
+        assert_eq!(
+            execute(&db, request(&["TTL", "key"])),
+            Response::ok(Value::Int(-1))
+        );
+        assert_eq!(
+            execute(&db, request(&["EXPIRE", "key", "10"])),
+            Response::ok(Value::Int(1))
+        );
+        tokio::time::advance(Duration::from_millis(2600)).await;
+        assert_eq!(
+            execute(&db, request(&["TTL", "key"])),
+            Response::ok(Value::Int(7))
+        );
+        assert_eq!(
+            execute(&db, request(&["PTTL", "key"])),
+            Response::ok(Value::Int(7400))
+        );
+        assert_eq!(
+            execute(&db, request(&["PERSIST", "key"])),
+            Response::ok(Value::Int(1))
+        );
+        assert_eq!(
+            execute(&db, request(&["PERSIST", "key"])),
+            Response::ok(Value::Int(0))
+        );
+        assert_eq!(
+            execute(&db, request(&["TTL", "key"])),
+            Response::ok(Value::Int(-1))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expired_keys_are_not_found() {
+        let db = Db::new();
+        execute(&db, request(&["SET", "a", "1", "PX", "100"]));
+        execute(&db, request(&["SET", "b", "2"]));
+        execute(&db, request(&["PEXPIRE", "b", "200"]));
+        tokio::time::advance(Duration::from_millis(150)).await;
+        assert_eq!(execute(&db, request(&["GET", "a"])), Response::nx());
+        assert_eq!(
+            execute(&db, request(&["GET", "b"])),
+            Response::ok(string("2"))
+        );
+        tokio::time::advance(Duration::from_millis(50)).await;
+        assert_eq!(execute(&db, request(&["GET", "b"])), Response::nx());
+        execute(&db, request(&["SET", "c", "3"]));
+        assert_eq!(
+            execute(&db, request(&["EXPIRE", "c", "-1"])),
+            Response::ok(Value::Int(1))
+        );
+        assert_eq!(execute(&db, request(&["GET", "c"])), Response::nx());
+    }
+
+    fn strings(strings: &[&str]) -> Value {
+        Value::Array(strings.iter().map(|s| string(s)).collect())
+    }
+
+    fn fruit_bowl() -> Db {
+        let db = Db::new();
+        execute(
+            &db,
+            request(&[
+                "ZADD",
+                "bowl",
+                "3",
+                "cherry",
+                "1",
+                "apple",
+                "2",
+                "banana",
+                "2",
+                "blueberry",
+                "5",
+                "strawberry",
+            ]),
+        );
+        db
+    }
+
+    #[test]
+    fn test_parsing_sorted_set_commands() {
+        assert_eq!(
+            Command::try_from(request(&["ZADD", "key", "1.5", "a", "-inf", "b"])),
+            Ok(Command::ZAdd {
+                key: Bytes::from_static(b"key"),
+                members: vec![
+                    (1.5, Bytes::from_static(b"a")),
+                    (f64::NEG_INFINITY, Bytes::from_static(b"b"))
+                ],
+            })
+        );
+        assert_eq!(
+            Command::try_from(request(&[
+                "ZREVRANGEBYSCORE",
+                "key",
+                "+inf",
+                "(1",
+                "WITHSCORES",
+                "LIMIT",
+                "1",
+                "2"
+            ])),
+            Ok(Command::ZRevRangeByScore {
+                key: Bytes::from_static(b"key"),
+                max: ScoreBound::Inclusive(f64::INFINITY),
+                min: ScoreBound::Exclusive(1.0),
+                with_scores: true,
+                limit: Some((1, 2)),
+            })
+        );
+        for strings in [
+            &["ZADD", "key"][..],
+            &["ZADD", "key", "1", "a", "2"],
+            &["ZREM", "key"],
+            &["ZSCORE", "key"],
+            &["ZRANGE", "key", "0"],
+        ] {
+            assert!(matches!(
+                Command::try_from(request(strings)),
+                Err(CommandError::WrongArity { .. })
+            ));
+        }
+        for strings in [
+            &["ZADD", "key", "nan", "a"][..],
+            &["ZADD", "key", "one", "a"],
+            &["ZCOUNT", "key", "(", "1"],
+        ] {
+            assert!(matches!(
+                Command::try_from(request(strings)),
+                Err(CommandError::NotAFloat { .. })
+            ));
+        }
+        assert_eq!(
+            Command::try_from(request(&["ZRANGE", "key", "0", "1", "LIMIT", "0", "1"])),
+            Err(CommandError::SyntaxError { name: "zrange" })
+        );
+    }
+
+    #[test]
+    fn test_zadd_zscore_and_zrem() {
+        let db = fruit_bowl();
+        assert_eq!(
+            execute(&db, request(&["ZADD", "bowl", "4", "apple", "6", "kiwi"])),
+            Response::ok(Value::Int(1))
+        );
+        assert_eq!(
+            execute(&db, request(&["ZSCORE", "bowl", "apple"])),
+            Response::ok(Value::Double(4.0))
+        );
+        assert_eq!(
+            execute(&db, request(&["ZSCORE", "bowl", "grape"])),
+            Response::nx()
+        );
+        assert_eq!(
+            execute(&db, request(&["ZCARD", "bowl"])),
+            Response::ok(Value::Int(6))
+        );
+        assert_eq!(
+            execute(&db, request(&["ZREM", "bowl", "apple", "grape", "kiwi"])),
+            Response::ok(Value::Int(2))
+        );
+        assert_eq!(
+            execute(&db, request(&["ZCARD", "missing"])),
+            not_found(Value::Int(0))
+        );
+    }
+
+    #[test]
+    fn test_zrank_and_zrange() {
+        let db = fruit_bowl();
+        assert_eq!(
+            execute(&db, request(&["ZRANK", "bowl", "banana"])),
+            Response::ok(Value::Int(1))
+        );
+        assert_eq!(
+            execute(&db, request(&["ZREVRANK", "bowl", "banana"])),
+            Response::ok(Value::Int(3))
+        );
+        assert_eq!(
+            execute(&db, request(&["ZRANK", "bowl", "grape"])),
+            Response::nx()
+        );
+        assert_eq!(
+            execute(&db, request(&["ZRANGE", "bowl", "1", "-2"])),
+            Response::ok(strings(&["banana", "blueberry", "cherry"]))
+        );
+        assert_eq!(
+            execute(&db, request(&["ZREVRANGE", "bowl", "0", "1", "WITHSCORES"])),
+            Response::ok(Value::Array(vec![
+                Value::Array(vec![string("strawberry"), Value::Double(5.0)]),
+                Value::Array(vec![string("cherry"), Value::Double(3.0)]),
+            ]))
+        );
+        assert_eq!(
+            execute(&db, request(&["ZRANGE", "bowl", "10", "20"])),
+            Response::ok(Value::Array(Vec::new()))
+        );
+        assert_eq!(
+            execute(&db, request(&["ZRANGE", "missing", "0", "-1"])),
+            not_found(Value::Array(Vec::new()))
+        );
+    }
+
+    #[test]
+    fn test_zrangebyscore_and_zcount() {
+        let db = fruit_bowl();
+        assert_eq!(
+            execute(&db, request(&["ZRANGEBYSCORE", "bowl", "2", "(5"])),
+            Response::ok(strings(&["banana", "blueberry", "cherry"]))
+        );
+        assert_eq!(
+            execute(
+                &db,
+                request(&["ZRANGEBYSCORE", "bowl", "-inf", "+inf", "LIMIT", "1", "2"])
+            ),
+            Response::ok(strings(&["banana", "blueberry"]))
+        );
+        assert_eq!(
+            execute(
+                &db,
+                request(&["ZREVRANGEBYSCORE", "bowl", "+inf", "(1", "LIMIT", "1", "-1"])
+            ),
+            Response::ok(strings(&["cherry", "blueberry", "banana"]))
+        );
+        assert_eq!(
+            execute(&db, request(&["ZCOUNT", "bowl", "(1", "3"])),
+            Response::ok(Value::Int(3))
+        );
+        assert_eq!(
+            execute(&db, request(&["ZCOUNT", "bowl", "4", "2"])),
+            Response::ok(Value::Int(0))
+        );
+    }
+
+    #[test]
+    fn test_sorted_set_commands_against_strings() {
+        let db = Db::new();
+        execute(&db, request(&["SET", "key", "value"]));
+        for strings in [
+            &["ZADD", "key", "1", "a"][..],
+            &["ZSCORE", "key", "a"],
+            &["ZRANGE", "key", "0", "-1"],
+        ] {
+            assert_eq!(
+                execute(&db, request(strings)).status_code,
+                ResponseStatusCode::WrongType
+            );
+        }
+        execute(&db, request(&["ZADD", "zset", "1", "a"]));
+        assert_eq!(
+            execute(&db, request(&["GET", "zset"])).status_code,
+            ResponseStatusCode::WrongType
+        );
+        execute(&db, request(&["ZREM", "zset", "a"]));
+        assert_eq!(execute(&db, request(&["GET", "zset"])), Response::nx());
+    }
+
+    #[test]
+    fn test_push_and_pop() {
+        let db = Db::new();
+        assert_eq!(
+            execute(&db, request(&["RPUSH", "queue", "b", "c"])),
+            Response::ok(Value::Int(2))
+        );
+        assert_eq!(
+            execute(&db, request(&["LPUSH", "queue", "a", "z"])),
+            Response::ok(Value::Int(4))
+        );
+        assert_eq!(
+            execute(&db, request(&["LRANGE", "queue", "0", "-1"])),
+            Response::ok(strings(&["z", "a", "b", "c"]))
+        );
+        assert_eq!(
+            execute(&db, request(&["LPOP", "queue"])),
+            Response::ok(string("z"))
+        );
+        assert_eq!(
+            execute(&db, request(&["RPOP", "queue"])),
+            Response::ok(string("c"))
+        );
+        assert_eq!(
+            execute(&db, request(&["LLEN", "queue"])),
+            Response::ok(Value::Int(2))
+        );
+        execute(&db, request(&["LPOP", "queue"]));
+        execute(&db, request(&["LPOP", "queue"]));
+        assert_eq!(execute(&db, request(&["LPOP", "queue"])), Response::nx());
+        assert_eq!(
+            execute(&db, request(&["LLEN", "queue"])),
+            not_found(Value::Int(0))
+        );
+    }
+
+    #[test]
+    fn test_lindex_lrange_and_ltrim() {
+        let db = Db::new();
+        execute(&db, request(&["RPUSH", "list", "a", "b", "c", "d", "e"]));
+        for (index, expected) in [
+            ("0", Some("a")),
+            ("-1", Some("e")),
+            ("5", None),
+            ("-6", None),
+        ] {
+            let response = execute(&db, request(&["LINDEX", "list", index]));
+            match expected {
+                Some(expected) => assert_eq!(response, Response::ok(string(expected))),
+                None => assert_eq!(response, Response::nx()),
+            }
+        }
+        assert_eq!(
+            execute(&db, request(&["LRANGE", "list", "-3", "100"])),
+            Response::ok(strings(&["c", "d", "e"]))
+        );
+        assert_eq!(
+            execute(&db, request(&["LTRIM", "list", "1", "-2"])),
+            Response::ok(string("OK"))
+        );
+        assert_eq!(
+            execute(&db, request(&["LRANGE", "list", "0", "-1"])),
+            Response::ok(strings(&["b", "c", "d"]))
+        );
+        execute(&db, request(&["LTRIM", "list", "2", "1"]));
+        assert_eq!(
+            execute(&db, request(&["LRANGE", "list", "0", "-1"])),
+            not_found(Value::Array(Vec::new()))
+        );
+    }
+
+    #[test]
+    fn test_list_commands_against_strings() {
+        let db = Db::new();
+        execute(&db, request(&["SET", "key", "value"]));
+        for strings in [
+            &["LPUSH", "key", "a"][..],
+            &["RPOP", "key"],
+            &["LRANGE", "key", "0", "-1"],
+            &["LLEN", "key"],
+        ] {
+            assert_eq!(
+                execute(&db, request(strings)),
+                Response::error(
+                    ResponseStatusCode::WrongType,
+                    "operation against a key holding the wrong kind of value"
+                )
+            );
+        }
+        assert_eq!(
+            execute(&db, request(&["GET", "key"])),
+            Response::ok(string("value"))
+        );
+    }
+
+    #[test]
+    fn test_hset_hget_and_hdel() {
+        let db = Db::new();
+        assert_eq!(
+            execute(
+                &db,
+                request(&["HSET", "user", "name", "ola", "city", "Krakow"])
+            ),
+            Response::ok(Value::Int(2))
+        );
+        assert_eq!(
+            execute(
+                &db,
+                request(&["HSET", "user", "city", "Gdansk", "age", "31"])
+            ),
+            Response::ok(Value::Int(1))
+        );
+        assert_eq!(
+            execute(&db, request(&["HGET", "user", "city"])),
+            Response::ok(string("Gdansk"))
+        );
+        assert_eq!(
+            execute(&db, request(&["HGET", "user", "email"])),
+            Response::nx()
+        );
+        assert_eq!(
+            execute(&db, request(&["HEXISTS", "user", "age"])),
+            Response::ok(Value::Int(1))
+        );
+        assert_eq!(
+            execute(&db, request(&["HLEN", "user"])),
+            Response::ok(Value::Int(3))
+        );
+        assert_eq!(
+            execute(&db, request(&["HDEL", "user", "age", "email"])),
+            Response::ok(Value::Int(1))
+        );
+        execute(&db, request(&["HDEL", "user", "name", "city"]));
+        assert_eq!(
+            execute(&db, request(&["HLEN", "user"])),
+            not_found(Value::Int(0))
+        );
+        assert!(matches!(
+            Command::try_from(request(&["HSET", "user", "name"])),
+            Err(CommandError::WrongArity { name: "hset" })
+        ));
+    }
+
+    #[test]
+    fn test_hgetall_returns_field_value_pairs() {
+        let db = Db::new();
+        execute(
+            &db,
+            request(&["HSET", "user", "name", "ola", "city", "Krakow"]),
+        );
+        let Response {
+            status_code: ResponseStatusCode::Ok,
+            value: Value::Array(mut pairs),
+        } = execute(&db, request(&["HGETALL", "user"]))
+        else {
+            panic!("expected an array of pairs");
+        };
+        pairs.sort_by_key(|pair| format!("{:?}", pair));
+        assert_eq!(
+            pairs,
+            vec![
+                Value::Array(vec![string("city"), string("Krakow")]),
+                Value::Array(vec![string("name"), string("ola")]),
+            ]
+        );
+        assert_eq!(
+            execute(&db, request(&["HGETALL", "missing"])),
+            not_found(Value::Array(Vec::new()))
+        );
+        execute(&db, request(&["SET", "key", "value"]));
+        assert_eq!(
+            execute(&db, request(&["HGET", "key", "name"])).status_code,
+            ResponseStatusCode::WrongType
+        );
+    }
+
+    fn sorted_members(response: Response) -> Vec<Value> {
+        let Value::Array(mut members) = response.value else {
+            panic!("expected an array of members");
+        };
+        members.sort_by_key(|member| format!("{:?}", member));
+        members
+    }
+
+    #[test]
+    fn test_sadd_srem_and_membership() {
+        let db = Db::new();
+        assert_eq!(
+            execute(&db, request(&["SADD", "tags", "rust", "db", "rust"])),
+            Response::ok(Value::Int(2))
+        );
+        assert_eq!(
+            execute(&db, request(&["SISMEMBER", "tags", "db"])),
+            Response::ok(Value::Int(1))
+        );
+        assert_eq!(
+            execute(&db, request(&["SISMEMBER", "tags", "go"])),
+            Response::ok(Value::Int(0))
+        );
+        assert_eq!(
+            execute(&db, request(&["SCARD", "tags"])),
+            Response::ok(Value::Int(2))
+        );
+        assert_eq!(
+            sorted_members(execute(&db, request(&["SMEMBERS", "tags"]))),
+            vec![string("db"), string("rust")]
+        );
+        assert_eq!(
+            execute(&db, request(&["SREM", "tags", "rust", "db", "go"])),
+            Response::ok(Value::Int(2))
+        );
+        assert_eq!(
+            execute(&db, request(&["SMEMBERS", "tags"])),
+            not_found(Value::Array(Vec::new()))
+        );
+        execute(&db, request(&["SET", "key", "value"]));
+        assert_eq!(
+            execute(&db, request(&["SADD", "key", "a"])).status_code,
+            ResponseStatusCode::WrongType
+        );
+    }
+
+    #[test]
+    fn test_set_algebra() {
+        let db = Db::new();
+        execute(&db, request(&["SADD", "a", "1", "2", "3", "x"]));
+        execute(&db, request(&["SADD", "b", "2", "3", "4"]));
+        execute(&db, request(&["SADD", "c", "3", "5"]));
+        assert_eq!(
+            sorted_members(execute(&db, request(&["SUNION", "a", "b", "missing"]))),
+            ["1", "2", "3", "4", "x"].map(string)
+        );
+        assert_eq!(
+            sorted_members(execute(&db, request(&["SINTER", "a", "b", "c"]))),
+            vec![string("3")]
+        );
+        assert_eq!(
+            sorted_members(execute(&db, request(&["SINTER", "a", "missing"]))),
+            Vec::new()
+        );
+        assert_eq!(
+            sorted_members(execute(&db, request(&["SDIFF", "a", "b", "c"]))),
+            ["1", "x"].map(string)
+        );
+        execute(&db, request(&["SET", "string", "value"]));
+        assert_eq!(
+            execute(&db, request(&["SUNION", "a", "string"])).status_code,
+            ResponseStatusCode::WrongType
+        );
+    }
+
+    #[test]
+    fn test_set_store_variants() {
+        let db = Db::new();
+        execute(&db, request(&["SADD", "a", "1", "2", "3"]));
+        execute(&db, request(&["SADD", "b", "3", "4"]));
+        assert_eq!(
+            execute(&db, request(&["SUNIONSTORE", "out", "a", "b"])),
+            Response::ok(Value::Int(4))
+        );
+        assert_eq!(
+            execute(&db, request(&["SINTERSTORE", "a", "a", "b"])),
+            Response::ok(Value::Int(1))
+        );
+        assert_eq!(
+            sorted_members(execute(&db, request(&["SMEMBERS", "a"]))),
+            vec![string("3")]
+        );
+        execute(&db, request(&["SET", "out2", "value"]));
+        assert_eq!(
+            execute(&db, request(&["SDIFFSTORE", "out2", "a", "out"])),
+            Response::ok(Value::Int(0))
+        );
+        assert_eq!(execute(&db, request(&["GET", "out2"])), Response::nx());
+        assert_eq!(
+            execute(&db, request(&["SCARD", "out"])),
+            Response::ok(Value::Int(4))
+        );
+    }
+
+    #[test]
+    fn test_incr_and_decr() {
+        let db = Db::new();
+        assert_eq!(
+            execute(&db, request(&["INCR", "counter"])),
+            Response::ok(Value::Int(1))
+        );
+        assert_eq!(
+            execute(&db, request(&["INCRBY", "counter", "41"])),
+            Response::ok(Value::Int(42))
+        );
+        assert_eq!(
+            execute(&db, request(&["DECR", "counter"])),
+            Response::ok(Value::Int(41))
+        );
+        assert_eq!(
+            execute(&db, request(&["GET", "counter"])),
+            Response::ok(string("41"))
+        );
+        execute(&db, request(&["SET", "max", "9223372036854775807"]));
+        assert_eq!(
+            execute(&db, request(&["INCR", "max"])),
+            Response::error(
+                ResponseStatusCode::Err,
+                "increment or decrement would overflow"
+            )
+        );
+        assert_eq!(
+            execute(&db, request(&["GET", "max"])),
+            Response::ok(string("9223372036854775807"))
+        );
+    }
+
+    #[test]
+    fn test_increments_keep_ttl() {
+        let db = Db::new();
+        execute(&db, request(&["SET", "counter", "10", "EX", "100"]));
+        execute(&db, request(&["INCR", "counter"]));
+        assert_eq!(
+            execute(&db, request(&["TTL", "counter"])),
+            Response::ok(Value::Int(100))
+        );
+    }
+
+    #[test]
+    fn test_incrbyfloat() {
+        let db = Db::new();
+        assert_eq!(
+            execute(&db, request(&["INCRBYFLOAT", "price", "10.5"])),
+            Response::ok(Value::Double(10.5))
+        );
+        assert_eq!(
+            execute(&db, request(&["INCRBYFLOAT", "price", "-0.5"])),
+            Response::ok(Value::Double(10.0))
+        );
+        assert_eq!(
+            execute(&db, request(&["GET", "price"])),
+            Response::ok(string("10"))
+        );
+        assert_eq!(
+            execute(&db, request(&["INCRBY", "price", "5"])),
+            Response::ok(Value::Int(15))
+        );
+        assert_eq!(
+            execute(&db, request(&["INCRBYFLOAT", "price", "inf"])).status_code,
+            ResponseStatusCode::Err
+        );
+    }
+
+    #[test]
+    fn test_increments_on_non_numeric_values() {
+        let db = Db::new();
+        execute(&db, request(&["SET", "name", "truskawka"]));
+        execute(&db, request(&["SET", "float", "1.5"]));
+        assert_eq!(
+            execute(&db, request(&["INCR", "name"])),
+            Response::error(
+                ResponseStatusCode::Err,
+                "value is not an integer or out of range"
+            )
+        );
+        assert_eq!(
+            execute(&db, request(&["INCR", "float"])).status_code,
+            ResponseStatusCode::Err
+        );
+        assert_eq!(
+            execute(&db, request(&["INCRBYFLOAT", "name", "1"])),
+            Response::error(ResponseStatusCode::Err, "value is not a valid float")
+        );
+        assert_eq!(
+            execute(&db, request(&["INCRBY", "name", "one"])),
+            Response::error(
+                ResponseStatusCode::Err,
+                "value 'one' is not an integer or out of range"
+            )
+        );
+        execute(&db, request(&["RPUSH", "list", "1"]));
+        assert_eq!(
+            execute(&db, request(&["INCR", "list"])).status_code,
+            ResponseStatusCode::WrongType
+        );
+    }
+
+    fn scan_all(db: &Db, command: &[&str], options: &[&str]) -> Vec<Value> {
+        let mut items = Vec::new();
+        let mut cursor = "0".to_string();
+        loop {
+            let mut strings = command.to_vec();
+            strings.push(&cursor);
+            strings.extend_from_slice(options);
+            let response = execute(db, request(&strings));
+            let Value::Array(reply) = response.value else {
+                panic!("expected a scan reply");
+            };
+            let [Value::Int(next), Value::Array(batch)] = reply.as_slice() else {
+                panic!("expected a cursor and a batch");
+            };
+            items.extend(batch.iter().cloned());
+            if *next == 0 {
+                break;
+            }
+            cursor = next.to_string();
+        }
+        items.sort_by_key(|item| format!("{:?}", item));
+        items.dedup();
+        items
+    }
+
+    #[test]
+    fn test_scan_returns_every_key() {
+        let db = Db::new();
+        for i in 0..200 {
+            let key = format!("key:{:03}", i);
+            execute(&db, request(&["SET", &key, "value"]));
+        }
+        let keys = scan_all(&db, &["SCAN"], &["COUNT", "7"]);
+        assert_eq!(keys.len(), 200);
+        let keys = scan_all(&db, &["SCAN"], &["MATCH", "key:1?0"]);
+        assert_eq!(
+            keys,
+            [
+                "key:100", "key:110", "key:120", "key:130", "key:140", "key:150", "key:160",
+                "key:170", "key:180", "key:190"
+            ]
+            .map(string)
+        );
+        assert_eq!(
+            Command::try_from(request(&["SCAN", "-1"])),
+            Err(CommandError::InvalidCursor {
+                argument: "-1".to_string()
+            })
+        );
+        assert_eq!(
+            Command::try_from(request(&["SCAN", "0", "COUNT", "0"])),
+            Err(CommandError::SyntaxError { name: "scan" })
+        );
+    }
+
+    #[test]
+    fn test_scan_while_keys_change() {
+        let db = Db::new();
+        for i in 0..100 {
+            execute(&db, request(&["SET", &format!("stable:{}", i), "value"]));
+        }
+        let mut seen = Vec::new();
+        let mut cursor = 0;
+        let mut round = 0;
+        loop {
+            let response = execute(&db, request(&["SCAN", &cursor.to_string(), "COUNT", "5"]));
+            let Value::Array(reply) = response.value else {
+                panic!("expected a scan reply");
+            };
+            let [Value::Int(next), Value::Array(batch)] = reply.as_slice() else {
+                panic!("expected a cursor and a batch");
+            };
+            seen.extend(batch.iter().cloned());
+            round += 1;
+            for i in 0..50 {
+                let key = format!("churn:{}:{}", round, i);
+                execute(&db, request(&["SET", &key, "value"]));
+                let key = format!("churn:{}:{}", round - 1, i);
+                execute(&db, request(&["DEL", &key]));
+            }
+            if *next == 0 {
+                break;
+            }
+            cursor = *next;
+        }
+        for i in 0..100 {
+            assert!(seen.contains(&string(&format!("stable:{}", i))));
+        }
+    }
+
+    #[test]
+    fn test_collection_scans() {
+        let db = Db::new();
+        for i in 0..50 {
+            let field = format!("field{}", i);
+            execute(&db, request(&["HSET", "hash", &field, "value"]));
+            execute(&db, request(&["SADD", "tags", &format!("tag{}", i)]));
+            execute(&db, request(&["SADD", "ids", &i.to_string()]));
+            execute(&db, request(&["ZADD", "zset", &i.to_string(), &field]));
+        }
+        assert_eq!(scan_all(&db, &["HSCAN", "hash"], &[]).len(), 50);
+        assert_eq!(
+            scan_all(&db, &["HSCAN", "hash"], &["MATCH", "field1"]),
+            vec![Value::Array(vec![string("field1"), string("value")])]
+        );
+        assert_eq!(scan_all(&db, &["SSCAN", "tags"], &["COUNT", "3"]).len(), 50);
+        assert_eq!(scan_all(&db, &["SSCAN", "ids"], &["MATCH", "4?"]).len(), 10);
+        assert_eq!(
+            scan_all(&db, &["ZSCAN", "zset"], &["MATCH", "field4[29]"]),
+            vec![
+                Value::Array(vec![string("field42"), Value::Double(42.0)]),
+                Value::Array(vec![string("field49"), Value::Double(49.0)]),
+            ]
+        );
+        assert_eq!(
+            execute(&db, request(&["SSCAN", "missing", "0"])),
+            not_found(scan_reply(0, Vec::new()))
+        );
+        assert_eq!(
+            execute(&db, request(&["HSCAN", "tags", "0"])).status_code,
+            ResponseStatusCode::WrongType
+        );
+    }
+
+    fn with_snapshot(path: std::path::PathBuf) -> Db {
+        Db::open(Persistence {
+            snapshot_path: Some(path),
+            aof: None,
+        })
+        .unwrap()
+    }
+
+    fn with_aof(dir: &tempfile::TempDir) -> Db {
+        Db::open(Persistence {
+            snapshot_path: None,
+            aof: Some(AofConfig::new(
+                dir.path().join("appendonly.aof"),
+                FsyncPolicy::Always,
+            )),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_save_and_load_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dump.snapshot");
+        let db = with_snapshot(path.clone());
+        execute(&db, request(&["SET", "string", "value"]));
+        execute(&db, request(&["SET", "expiring", "value", "EX", "100"]));
+        execute(&db, request(&["RPUSH", "list", "a", "b"]));
+        execute(&db, request(&["HSET", "hash", "field", "value"]));
+        execute(&db, request(&["SADD", "set", "1", "x"]));
+        execute(&db, request(&["ZADD", "zset", "2", "b", "1", "a"]));
+        assert_eq!(execute(&db, request(&["SAVE"])), Response::ok(string("OK")));
+        let db = with_snapshot(path);
+        assert_eq!(
+            execute(&db, request(&["GET", "string"])),
+            Response::ok(string("value"))
+        );
+        assert_eq!(
+            execute(&db, request(&["TTL", "expiring"])),
+            Response::ok(Value::Int(100))
+        );
+        assert_eq!(
+            execute(&db, request(&["LRANGE", "list", "0", "-1"])),
+            Response::ok(strings(&["a", "b"]))
+        );
+        assert_eq!(
+            execute(&db, request(&["HGET", "hash", "field"])),
+            Response::ok(string("value"))
+        );
+        assert_eq!(
+            execute(&db, request(&["SCARD", "set"])),
+            Response::ok(Value::Int(2))
+        );
+        assert_eq!(
+            execute(&db, request(&["ZRANGE", "zset", "0", "-1"])),
+            Response::ok(strings(&["a", "b"]))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bgsave() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dump.snapshot");
+        let db = with_snapshot(path.clone());
+        execute(&db, request(&["SET", "key", "value"]));
+        assert_eq!(
+            execute(&db, request(&["BGSAVE"])),
+            Response::ok(string("Background saving started"))
+        );
+        execute(&db, request(&["SET", "later", "value"]));
+        loop {
+            match execute(&db, request(&["SAVE"])).status_code {
+                ResponseStatusCode::Busy => tokio::task::yield_now().await,
+                status_code => {
+                    assert_eq!(status_code, ResponseStatusCode::Ok);
+                    break;
+                }
+            }
+        }
+        let db = with_snapshot(path);
+        assert_eq!(
+            execute(&db, request(&["GET", "later"])),
+            Response::ok(string("value"))
+        );
+    }
+
+    #[test]
+    fn test_save_without_snapshot_file() {
+        let db = Db::new();
+        assert_eq!(
+            execute(&db, request(&["SAVE"])),
+            Response::error(ResponseStatusCode::Err, "snapshots are disabled")
+        );
+    }
+
+    #[test]
+    fn test_writes_are_replayed_from_the_append_only_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = with_aof(&dir);
+        execute(&db, request(&["SET", "a", "1"]));
+        execute(&db, request(&["INCRBY", "a", "10"]));
+        execute(&db, request(&["RPUSH", "list", "x", "y", "z"]));
+        execute(&db, request(&["LPOP", "list"]));
+        execute(&db, request(&["ZADD", "zset", "1.5", "m"]));
+        execute(&db, request(&["SADD", "set", "1", "2"]));
+        execute(&db, request(&["SINTERSTORE", "copy", "set"]));
+        execute(&db, request(&["HSET", "hash", "f", "v"]));
+        execute(&db, request(&["SET", "gone", "1"]));
+        execute(&db, request(&["DEL", "gone"]));
+        drop(db);
+        let db = with_aof(&dir);
+        assert_eq!(
+            execute(&db, request(&["GET", "a"])),
+            Response::ok(string("11"))
+        );
+        assert_eq!(
+            execute(&db, request(&["LRANGE", "list", "0", "-1"])),
+            Response::ok(strings(&["y", "z"]))
+        );
+        assert_eq!(
+            execute(&db, request(&["ZSCORE", "zset", "m"])),
+            Response::ok(Value::Double(1.5))
+        );
+        assert_eq!(
+            execute(&db, request(&["SCARD", "copy"])),
+            Response::ok(Value::Int(2))
+        );
+        assert_eq!(
+            execute(&db, request(&["HGET", "hash", "f"])),
+            Response::ok(string("v"))
+        );
+        assert_eq!(execute(&db, request(&["GET", "gone"])), Response::nx());
+    }
+
+    #[tokio::test]
+    async fn test_rewrite_append_only_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = with_aof(&dir);
+        for _ in 0..50 {
+            execute(&db, request(&["INCR", "counter"]));
+        }
+        execute(&db, request(&["EXPIRE", "counter", "100"]));
+        execute(&db, request(&["RPUSH", "list", "x", "y", "z"]));
+        execute(&db, request(&["ZADD", "zset", "1.5", "m", "-inf", "n"]));
+        execute(&db, request(&["SADD", "set", "1", "a"]));
+        execute(&db, request(&["HSET", "hash", "f", "v"]));
+        let handle = db.lock().rewrite_append_only_file().unwrap();
+        handle.await.unwrap();
+        drop(db);
+        let config = AofConfig::new(dir.path().join("appendonly.aof"), FsyncPolicy::Never);
+        let (_, requests) = AppendOnlyFile::open(&config).unwrap();
+        assert_eq!(requests.len(), 6);
+        let db = with_aof(&dir);
+        assert_eq!(
+            execute(&db, request(&["GET", "counter"])),
+            Response::ok(string("50"))
+        );
+        assert_eq!(
+            execute(&db, request(&["TTL", "counter"])),
+            Response::ok(Value::Int(100))
+        );
+        assert_eq!(
+            execute(&db, request(&["LRANGE", "list", "0", "-1"])),
+            Response::ok(strings(&["x", "y", "z"]))
+        );
+        assert_eq!(
+            execute(&db, request(&["ZSCORE", "zset", "n"])),
+            Response::ok(Value::Double(f64::NEG_INFINITY))
+        );
+        assert_eq!(
+            execute(&db, request(&["SISMEMBER", "set", "a"])),
+            Response::ok(Value::Int(1))
+        );
+        assert_eq!(
+            execute(&db, request(&["HGET", "hash", "f"])),
+            Response::ok(string("v"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bgrewriteaof() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = with_aof(&dir);
+        assert_eq!(
+            execute(&db, request(&["BGREWRITEAOF"])),
+            Response::ok(string("Background append only file rewriting started"))
+        );
+        assert_eq!(
+            execute(&Db::new(), request(&["BGREWRITEAOF"])),
+            Response::error(ResponseStatusCode::Err, "append-only file is disabled")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_append_only_file_is_rewritten_after_growth() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = AofConfig {
+            rewrite_min_size: 1024,
+            ..AofConfig::new(dir.path().join("appendonly.aof"), FsyncPolicy::Never)
+        };
+        let db = Db::open(Persistence {
+            snapshot_path: None,
+            aof: Some(config.clone()),
+        })
+        .unwrap();
+        for _ in 0..100 {
+            execute(&db, request(&["INCR", "counter"]));
+        }
+        let grown = std::fs::metadata(&config.path).unwrap().len();
+        tokio::spawn(db.clone().sync_append_only_file());
+        let mut size = grown;
+        for _ in 0..500 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            size = std::fs::metadata(&config.path).unwrap().len();
+            if size < grown {
+                break;
+            }
+        }
+        assert!(size < grown);
+        execute(&db, request(&["INCR", "counter"]));
+        let (_, requests) = AppendOnlyFile::open(&config).unwrap();
+        assert_eq!(
+            requests,
+            vec![
+                request(&["SET", "counter", "100"]),
+                request(&["INCR", "counter"])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_only_successful_writes_are_logged() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = with_aof(&dir);
+        execute(&db, request(&["SET", "a", "1"]));
+        execute(&db, request(&["GET", "a"]));
+        execute(&db, request(&["LPUSH", "a", "x"]));
+        execute(&db, request(&["INCRBYFLOAT", "missing", "nan"]));
+        execute(&db, request(&["DEL", "missing"]));
+        execute(&db, request(&["NOPE"]));
+        drop(db);
+        let config = AofConfig::new(dir.path().join("appendonly.aof"), FsyncPolicy::Never);
+        let (_, requests) = AppendOnlyFile::open(&config).unwrap();
+        assert_eq!(requests, vec![request(&["SET", "a", "1"])]);
+    }
+
+    #[test]
+    fn test_relative_expiries_are_logged_as_absolute() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = with_aof(&dir);
+        execute(&db, request(&["SET", "a", "1", "EX", "100"]));
+        execute(&db, request(&["SET", "b", "2"]));
+        execute(&db, request(&["PEXPIRE", "b", "50000"]));
+        execute(&db, request(&["SET", "c", "3"]));
+        execute(&db, request(&["EXPIRE", "c", "-1"]));
+        drop(db);
+        let config = AofConfig::new(dir.path().join("appendonly.aof"), FsyncPolicy::Never);
+        let (_, requests) = AppendOnlyFile::open(&config).unwrap();
+        let names: Vec<_> = requests
+            .iter()
+            .map(|request| String::from_utf8_lossy(&request.strings[0]).into_owned())
+            .collect();
+        assert_eq!(
+            names,
+            vec!["SET", "PEXPIREAT", "SET", "PEXPIREAT", "SET", "DEL"]
+        );
+        assert_eq!(requests[0], request(&["SET", "a", "1"]));
+        let db = with_aof(&dir);
+        assert_eq!(
+            execute(&db, request(&["TTL", "a"])),
+            Response::ok(Value::Int(100))
+        );
+        assert_eq!(
+            execute(&db, request(&["TTL", "b"])),
+            Response::ok(Value::Int(50))
+        );
+        assert_eq!(execute(&db, request(&["GET", "c"])), Response::nx());
+    }
+
+    #[test]
+    fn test_pexpireat() {
+        let db = Db::new();
+        execute(&db, request(&["SET", "a", "1"]));
+        let timestamp =
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap() + Duration::from_secs(30);
+        let timestamp = timestamp.as_millis().to_string();
+        assert_eq!(
+            execute(&db, request(&["PEXPIREAT", "a", &timestamp])),
+            Response::ok(Value::Int(1))
+        );
+        assert_eq!(
+            execute(&db, request(&["TTL", "a"])),
+            Response::ok(Value::Int(30))
+        );
+        assert_eq!(
+            execute(&db, request(&["PEXPIREAT", "a", "1000"])),
+            Response::ok(Value::Int(1))
+        );
+        assert_eq!(execute(&db, request(&["GET", "a"])), Response::nx());
+    }
+}
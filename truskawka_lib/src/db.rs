@@ -0,0 +1,936 @@
+use std::collections::{BTreeSet, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::aof::{AofConfig, AofError, AppendOnlyFile, FsyncPolicy};
+use crate::command;
+use crate::dict::Dict;
+use crate::protocol::Request;
+use crate::replication::Replication;
+use crate::set::Set;
+use crate::snapshot::{SnapshotEntry, SnapshotError, Snapshotter};
+use crate::sorted_set::SortedSet;
+
+const MAX_EXPIRATIONS_PER_SWEEP: usize = 1024;
+const CAPTURE_BATCH_SIZE: usize = 1024;
+// Longer TTLs are shortened to this so that a deadline can always be computed.
+const MAX_TTL: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("operation against a key holding the wrong kind of value")]
+pub struct WrongType;
+
+#[derive(thiserror::Error, Debug)]
+pub enum PersistenceError {
+    #[error("snapshot error")]
+    Snapshot {
+        #[from]
+        source: SnapshotError,
+    },
+    #[error("append-only file error")]
+    AppendOnly {
+        #[from]
+        source: AofError,
+    },
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Persistence {
+    pub snapshot_path: Option<PathBuf>,
+    pub aof: Option<AofConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Object {
+    String(Bytes),
+    List(VecDeque<Bytes>),
+    Hash(Dict<Bytes, Bytes>),
+    Set(Set),
+    SortedSet(SortedSet),
+}
+
+pub trait ObjectKind: Default {
+    fn from_object(object: &Object) -> Option<&Self>;
+    fn from_object_mut(object: &mut Object) -> Option<&mut Self>;
+    fn into_object(self) -> Object;
+
+    fn is_drained(&self) -> bool {
+        false
+    }
+}
+
+impl ObjectKind for Bytes {
+    fn from_object(object: &Object) -> Option<&Self> {
+        match object {
+            Object::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    fn from_object_mut(object: &mut Object) -> Option<&mut Self> {
+        match object {
+            Object::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    fn into_object(self) -> Object {
+        Object::String(self)
+    }
+}
+
+impl ObjectKind for VecDeque<Bytes> {
+    fn from_object(object: &Object) -> Option<&Self> {
+        match object {
+            Object::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    fn from_object_mut(object: &mut Object) -> Option<&mut Self> {
+        match object {
+            Object::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    fn into_object(self) -> Object {
+        Object::List(self)
+    }
+
+    fn is_drained(&self) -> bool {
+        self.is_empty()
+    }
+}
+
+impl ObjectKind for Dict<Bytes, Bytes> {
+    fn from_object(object: &Object) -> Option<&Self> {
+        match object {
+            Object::Hash(hash) => Some(hash),
+            _ => None,
+        }
+    }
+
+    fn from_object_mut(object: &mut Object) -> Option<&mut Self> {
+        match object {
+            Object::Hash(hash) => Some(hash),
+            _ => None,
+        }
+    }
+
+    fn into_object(self) -> Object {
+        Object::Hash(self)
+    }
+
+    fn is_drained(&self) -> bool {
+        self.is_empty()
+    }
+}
+
+impl ObjectKind for Set {
+    fn from_object(object: &Object) -> Option<&Self> {
+        match object {
+            Object::Set(set) => Some(set),
+            _ => None,
+        }
+    }
+
+    fn from_object_mut(object: &mut Object) -> Option<&mut Self> {
+        match object {
+            Object::Set(set) => Some(set),
+            _ => None,
+        }
+    }
+
+    fn into_object(self) -> Object {
+        Object::Set(self)
+    }
+
+    fn is_drained(&self) -> bool {
+        self.is_empty()
+    }
+}
+
+impl ObjectKind for SortedSet {
+    fn from_object(object: &Object) -> Option<&Self> {
+        match object {
+            Object::SortedSet(set) => Some(set),
+            _ => None,
+        }
+    }
+
+    fn from_object_mut(object: &mut Object) -> Option<&mut Self> {
+        match object {
+            Object::SortedSet(set) => Some(set),
+            _ => None,
+        }
+    }
+
+    fn into_object(self) -> Object {
+        Object::SortedSet(self)
+    }
+
+    fn is_drained(&self) -> bool {
+        self.is_empty()
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Db {
+    shared: Arc<Shared>,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    expirations_changed: Notify,
+    snapshotter: Option<Snapshotter>,
+    aof: Option<AppendOnlyFile>,
+    replication: Replication,
+}
+
+#[derive(Default)]
+struct State {
+    entries: Dict<Bytes, Entry>,
+    expirations: BTreeSet<(Instant, Bytes)>,
+    captures: Vec<PendingCapture>,
+    next_capture_id: u64,
+}
+
+struct Entry {
+    object: Object,
+    expires_at: Option<Instant>,
+}
+
+impl Entry {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+// A capture scans the keyspace in batches. Keys written before the scan reaches
+// them have their old value preserved first, so the result is the keyspace as
+// it was when the capture started.
+struct PendingCapture {
+    id: u64,
+    cursor: u64,
+    captured: HashSet<Bytes>,
+    entries: Vec<SnapshotEntry>,
+    now: Instant,
+    wall_clock: SystemTime,
+}
+
+impl PendingCapture {
+    fn push(&mut self, key: &Bytes, entry: &Entry) {
+        if !entry.is_expired(self.now) {
+            let entry = snapshot_entry(key, entry, self.now, self.wall_clock);
+            self.entries.push(entry);
+        }
+    }
+}
+
+fn deadline(ttl: Duration) -> Instant {
+    Instant::now() + ttl.min(MAX_TTL)
+}
+
+fn snapshot_entry(
+    key: &Bytes,
+    entry: &Entry,
+    now: Instant,
+    wall_clock: SystemTime,
+) -> SnapshotEntry {
+    SnapshotEntry {
+        key: key.clone(),
+        object: entry.object.clone(),
+        expires_at: entry
+            .expires_at
+            .map(|expires_at| wall_clock + (expires_at - now)),
+    }
+}
+
+impl State {
+    fn entry(&mut self, key: &Bytes) -> Option<&mut Entry> {
+        if self.entries.get(key)?.is_expired(Instant::now()) {
+            log::debug!("Lazily expiring key {:?}", key);
+            self.remove(key);
+            return None;
+        }
+        self.entries.get_mut(key)
+    }
+
+    fn entry_mut(&mut self, key: &Bytes) -> Option<&mut Entry> {
+        self.entry(key)?;
+        self.preserve(key);
+        self.entries.get_mut(key)
+    }
+
+    fn insert(&mut self, key: Bytes, entry: Entry) -> bool {
+        let expires_at = entry.expires_at;
+        self.preserve(&key);
+        if let Some(previous) = self.entries.insert(key.clone(), entry) {
+            self.unschedule_expiration(&key, previous.expires_at);
+        }
+        expires_at.is_some_and(|when| self.schedule_expiration(key, when))
+    }
+
+    fn remove(&mut self, key: &Bytes) -> Option<Entry> {
+        self.preserve(key);
+        let entry = self.entries.remove(key)?;
+        self.unschedule_expiration(key, entry.expires_at);
+        Some(entry)
+    }
+
+    fn set_expiry(&mut self, key: &Bytes, expires_at: Option<Instant>) -> bool {
+        self.preserve(key);
+        let Some(entry) = self.entries.get_mut(key) else {
+            return false;
+        };
+        let previous = std::mem::replace(&mut entry.expires_at, expires_at);
+        self.unschedule_expiration(key, previous);
+        expires_at.is_some_and(|when| self.schedule_expiration(key.clone(), when))
+    }
+
+    fn next_expiration(&self) -> Option<Instant> {
+        self.expirations.first().map(|(when, _)| *when)
+    }
+
+    fn schedule_expiration(&mut self, key: Bytes, when: Instant) -> bool {
+        let earliest = self.next_expiration().is_none_or(|next| when < next);
+        self.expirations.insert((when, key));
+        earliest
+    }
+
+    fn unschedule_expiration(&mut self, key: &Bytes, when: Option<Instant>) {
+        if let Some(when) = when {
+            self.expirations.remove(&(when, key.clone()));
+        }
+    }
+
+    fn purge_expired(&mut self, now: Instant) -> usize {
+        let mut purged = 0;
+        for _ in 0..MAX_EXPIRATIONS_PER_SWEEP {
+            match self.expirations.first() {
+                Some((when, _)) if *when <= now => {}
+                _ => break,
+            }
+            let (_, key) = self.expirations.pop_first().unwrap();
+            self.preserve(&key);
+            self.entries.remove(&key);
+            purged += 1;
+        }
+        purged
+    }
+
+    fn clear(&mut self) {
+        if !self.captures.is_empty() {
+            let keys: Vec<Bytes> = self.entries.keys().cloned().collect();
+            keys.iter().for_each(|key| self.preserve(key));
+        }
+        self.entries = Dict::new();
+        self.expirations.clear();
+    }
+
+    fn preserve(&mut self, key: &Bytes) {
+        for capture in &mut self.captures {
+            if capture.captured.insert(key.clone()) {
+                if let Some(entry) = self.entries.get(key) {
+                    capture.push(key, entry);
+                }
+            }
+        }
+    }
+
+    fn start_capture(&mut self) -> u64 {
+        let id = self.next_capture_id;
+        self.next_capture_id += 1;
+        self.captures.push(PendingCapture {
+            id,
+            cursor: 0,
+            captured: HashSet::new(),
+            entries: Vec::new(),
+            now: Instant::now(),
+            wall_clock: SystemTime::now(),
+        });
+        id
+    }
+
+    fn continue_capture(&mut self, id: u64) -> Option<Vec<SnapshotEntry>> {
+        let index = self
+            .captures
+            .iter()
+            .position(|capture| capture.id == id)
+            .expect("capture is in progress");
+        let capture = &mut self.captures[index];
+        let cursor = self
+            .entries
+            .scan_batch(capture.cursor, CAPTURE_BATCH_SIZE, |key, entry| {
+                if capture.captured.insert(key.clone()) {
+                    capture.push(key, entry);
+                }
+            });
+        capture.cursor = cursor;
+        match cursor {
+            0 => Some(self.captures.swap_remove(index).entries),
+            _ => None,
+        }
+    }
+
+    fn cancel_capture(&mut self, id: u64) {
+        self.captures.retain(|capture| capture.id != id);
+    }
+}
+
+impl Db {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn open(persistence: Persistence) -> Result<Self, PersistenceError> {
+        let snapshotter = persistence.snapshot_path.map(Snapshotter::new);
+        let (aof, requests) = match &persistence.aof {
+            Some(config) => {
+                let (aof, requests) = AppendOnlyFile::open(config)?;
+                (Some(aof), Some(requests))
+            }
+            None => (None, None),
+        };
+        let entries = match (&snapshotter, &requests) {
+            (Some(snapshotter), None) => snapshotter.load()?,
+            _ => Vec::new(),
+        };
+        let db = Db {
+            shared: Arc::new(Shared {
+                snapshotter,
+                aof,
+                ..Default::default()
+            }),
+        };
+        let mut keyspace = db.lock();
+        match requests {
+            Some(requests) => {
+                let replayed = requests.len();
+                for request in requests {
+                    command::apply(&mut keyspace, request);
+                }
+                log::info!("Replayed {} requests from the append-only file", replayed);
+            }
+            None => {
+                let loaded = keyspace.restore(entries);
+                log::info!("Loaded {} keys from snapshot", loaded);
+            }
+        }
+        drop(keyspace);
+        Ok(db)
+    }
+
+    pub fn lock(&self) -> Keyspace<'_> {
+        Keyspace {
+            state: self.shared.state.lock().unwrap(),
+            shared: &self.shared,
+        }
+    }
+
+    pub fn replication(&self) -> &Replication {
+        &self.shared.replication
+    }
+
+    pub async fn purge_expired_keys(self) {
+        loop {
+            let (purged, next_expiration) = {
+                let mut state = self.shared.state.lock().unwrap();
+                let purged = state.purge_expired(Instant::now());
+                (purged, state.next_expiration())
+            };
+            if purged > 0 {
+                log::debug!("Purged {} expired keys", purged);
+            }
+            match next_expiration {
+                Some(when) if when <= Instant::now() => tokio::task::yield_now().await,
+                Some(when) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(when) => {}
+                        _ = self.shared.expirations_changed.notified() => {}
+                    }
+                }
+                None => self.shared.expirations_changed.notified().await,
+            }
+        }
+    }
+
+    pub fn check_writable(&self) -> Result<(), AofError> {
+        match &self.shared.aof {
+            Some(aof) => aof.check_writable(),
+            None => Ok(()),
+        }
+    }
+
+    pub async fn sync_append_only_file(self) {
+        let Some(aof) = &self.shared.aof else {
+            return;
+        };
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            if let Err(e) = aof.retry_pending() {
+                log::error!("Append-only file is still not writable: {:?}", e);
+                continue;
+            }
+            if aof.should_rewrite() {
+                log::info!("Append-only file has grown, starting a rewrite");
+                if let Err(e) = self.lock().rewrite_append_only_file() {
+                    log::error!("Failed to start append-only file rewrite: {:?}", e);
+                }
+            }
+            if aof.fsync_policy() != FsyncPolicy::EverySec {
+                continue;
+            }
+            let file = match aof.sync_handle() {
+                Ok(Some(file)) => file,
+                Ok(None) => continue,
+                Err(e) => {
+                    log::error!("Failed to sync the append-only file: {:?}", e);
+                    continue;
+                }
+            };
+            match tokio::task::spawn_blocking(move || file.sync_data()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => log::error!("Failed to sync the append-only file: {:?}", e),
+                Err(e) => log::error!("Append-only file sync task failed: {:?}", e),
+            }
+        }
+    }
+}
+
+pub struct Capture {
+    shared: Arc<Shared>,
+    id: u64,
+}
+
+impl Capture {
+    pub async fn finish(self) -> Vec<SnapshotEntry> {
+        loop {
+            let entries = self.shared.state.lock().unwrap().continue_capture(self.id);
+            match entries {
+                Some(entries) => return entries,
+                None => tokio::task::yield_now().await,
+            }
+        }
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.shared.state.lock() {
+            state.cancel_capture(self.id);
+        }
+    }
+}
+
+pub struct Keyspace<'a> {
+    state: MutexGuard<'a, State>,
+    shared: &'a Arc<Shared>,
+}
+
+impl Keyspace<'_> {
+    pub fn get<T: ObjectKind>(&mut self, key: &Bytes) -> Result<Option<&T>, WrongType> {
+        match self.state.entry(key) {
+            Some(entry) => T::from_object(&entry.object).map(Some).ok_or(WrongType),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set(&mut self, key: Bytes, object: Object, ttl: Option<Duration>) {
+        let expires_at = ttl.map(deadline);
+        let earliest = self.state.insert(key, Entry { object, expires_at });
+        self.notify_expirations_changed(earliest);
+    }
+
+    pub fn update<T: ObjectKind, R>(
+        &mut self,
+        key: &Bytes,
+        f: impl FnOnce(&mut T) -> R,
+    ) -> Result<Option<R>, WrongType> {
+        let Some(entry) = self.state.entry_mut(key) else {
+            return Ok(None);
+        };
+        let object = T::from_object_mut(&mut entry.object).ok_or(WrongType)?;
+        let result = f(object);
+        if object.is_drained() {
+            self.state.remove(key);
+        }
+        Ok(Some(result))
+    }
+
+    pub fn upsert<T: ObjectKind, R>(
+        &mut self,
+        key: &Bytes,
+        f: impl FnOnce(&mut T) -> R,
+    ) -> Result<R, WrongType> {
+        if self.state.entry(key).is_none() {
+            let entry = Entry {
+                object: T::default().into_object(),
+                expires_at: None,
+            };
+            self.state.insert(key.clone(), entry);
+        }
+        let result = self.update(key, f)?;
+        Ok(result.expect("key was just inserted"))
+    }
+
+    pub fn del(&mut self, keys: &[Bytes]) -> usize {
+        keys.iter()
+            .filter(|key| self.state.entry(key).is_some() && self.state.remove(key).is_some())
+            .count()
+    }
+
+    pub fn expire(&mut self, key: &Bytes, ttl: Duration) -> bool {
+        if self.state.entry(key).is_none() {
+            return false;
+        }
+        if ttl.is_zero() {
+            self.state.remove(key);
+            return true;
+        }
+        let earliest = self.state.set_expiry(key, Some(deadline(ttl)));
+        self.notify_expirations_changed(earliest);
+        true
+    }
+
+    pub fn expire_at(&mut self, key: &Bytes, when: SystemTime) -> bool {
+        let ttl = when.duration_since(SystemTime::now()).unwrap_or_default();
+        self.expire(key, ttl)
+    }
+
+    pub fn ttl(&mut self, key: &Bytes) -> Option<Option<Duration>> {
+        let entry = self.state.entry(key)?;
+        Some(
+            entry
+                .expires_at
+                .map(|expires_at| expires_at.saturating_duration_since(Instant::now())),
+        )
+    }
+
+    pub fn persist(&mut self, key: &Bytes) -> Option<bool> {
+        let had_expiry = self.state.entry(key)?.expires_at.is_some();
+        self.state.set_expiry(key, None);
+        Some(had_expiry)
+    }
+
+    pub fn flush(&mut self) {
+        self.state.clear();
+    }
+
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<Bytes>) {
+        let now = Instant::now();
+        let mut keys = Vec::new();
+        let cursor = self.state.entries.scan_batch(cursor, count, |key, entry| {
+            if !entry.is_expired(now) {
+                keys.push(key.clone());
+            }
+        });
+        (cursor, keys)
+    }
+
+    pub fn snapshot(&self) -> Vec<SnapshotEntry> {
+        let now = Instant::now();
+        let wall_clock = SystemTime::now();
+        self.state
+            .entries
+            .iter()
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .map(|(key, entry)| snapshot_entry(key, entry, now, wall_clock))
+            .collect()
+    }
+
+    pub fn capture(&mut self) -> Capture {
+        Capture {
+            shared: self.shared.clone(),
+            id: self.state.start_capture(),
+        }
+    }
+
+    pub fn restore(&mut self, entries: Vec<SnapshotEntry>) -> usize {
+        let wall_clock = SystemTime::now();
+        let mut restored = 0;
+        for entry in entries {
+            let ttl = match entry.expires_at.map(|when| when.duration_since(wall_clock)) {
+                Some(Ok(ttl)) if !ttl.is_zero() => Some(ttl),
+                Some(_) => continue,
+                None => None,
+            };
+            self.set(entry.key, entry.object, ttl);
+            restored += 1;
+        }
+        restored
+    }
+
+    pub fn save(&self) -> Result<(), SnapshotError> {
+        let snapshotter = self.snapshotter()?;
+        snapshotter.save(&self.snapshot())
+    }
+
+    pub fn save_in_background(&mut self) -> Result<JoinHandle<()>, SnapshotError> {
+        let shared = self.shared;
+        let snapshotter = shared.snapshotter.as_ref().ok_or(SnapshotError::Disabled)?;
+        snapshotter.save_in_background(|| self.capture().finish())
+    }
+
+    pub fn propagate(&self, requests: &[Request]) -> Result<(), AofError> {
+        self.shared.replication.feed(requests);
+        match &self.shared.aof {
+            Some(aof) => aof.append(requests),
+            None => Ok(()),
+        }
+    }
+
+    pub fn replication(&self) -> &Replication {
+        &self.shared.replication
+    }
+
+    pub fn rewrite_append_only_file(&mut self) -> Result<JoinHandle<()>, AofError> {
+        let shared = self.shared;
+        let aof = shared.aof.as_ref().ok_or(AofError::Disabled)?;
+        aof.rewrite_in_background(|| self.capture().finish())
+    }
+
+    fn snapshotter(&self) -> Result<&Snapshotter, SnapshotError> {
+        self.shared
+            .snapshotter
+            .as_ref()
+            .ok_or(SnapshotError::Disabled)
+    }
+
+    fn notify_expirations_changed(&self, earliest: bool) {
+        if earliest {
+            self.shared.expirations_changed.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{ResponseStatusCode, Value};
+
+    fn key(key: &'static str) -> Bytes {
+        Bytes::from_static(key.as_bytes())
+    }
+
+    fn set(db: &Db, key: Bytes, value: Bytes, ttl: Option<Duration>) {
+        db.lock().set(key, Object::String(value), ttl);
+    }
+
+    fn get(db: &Db, key: &Bytes) -> Option<Bytes> {
+        db.lock().get::<Bytes>(key).unwrap().cloned()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keys_expire_lazily_on_access() {
+        let db = Db::new();
+        set(&db, key("a"), key("1"), Some(Duration::from_secs(10)));
+        set(&db, key("b"), key("2"), None);
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert_eq!(get(&db, &key("a")), Some(key("1")));
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert_eq!(get(&db, &key("a")), None);
+        assert_eq!(get(&db, &key("b")), Some(key("2")));
+        assert!(!db
+            .shared
+            .state
+            .lock()
+            .unwrap()
+            .entries
+            .contains_key(&key("a")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expired_keys_are_purged_in_background() {
+        let db = Db::new();
+        tokio::spawn(db.clone().purge_expired_keys());
+        set(&db, key("a"), key("1"), Some(Duration::from_secs(60)));
+        set(&db, key("b"), key("2"), Some(Duration::from_secs(1)));
+        set(&db, key("c"), key("3"), None);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(db.shared.state.lock().unwrap().entries.len(), 2);
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(db.shared.state.lock().unwrap().entries.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stale_expirations_do_not_remove_keys() {
+        let db = Db::new();
+        tokio::spawn(db.clone().purge_expired_keys());
+        set(&db, key("a"), key("1"), Some(Duration::from_secs(1)));
+        set(&db, key("a"), key("2"), None);
+        set(&db, key("b"), key("3"), Some(Duration::from_secs(1)));
+        assert_eq!(db.lock().persist(&key("b")), Some(true));
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(db.shared.state.lock().unwrap().entries.len(), 2);
+    }
+
+    #[test]
+    fn test_expirations_stay_bounded() {
+        let db = Db::new();
+        set(&db, key("a"), key("1"), None);
+        for seconds in 1..1000 {
+            assert!(db.lock().expire(&key("a"), Duration::from_secs(seconds)));
+        }
+        set(&db, key("b"), key("2"), Some(Duration::from_secs(5)));
+        set(&db, key("b"), key("3"), Some(Duration::from_secs(10)));
+        set(&db, key("c"), key("4"), Some(Duration::from_secs(5)));
+        assert_eq!(db.shared.state.lock().unwrap().expirations.len(), 3);
+        assert_eq!(db.lock().persist(&key("a")), Some(true));
+        assert_eq!(db.lock().del(&[key("b")]), 1);
+        set(&db, key("c"), key("5"), None);
+        assert!(db.shared.state.lock().unwrap().expirations.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ttl() {
+        let db = Db::new();
+        assert_eq!(db.lock().ttl(&key("a")), None);
+        set(&db, key("a"), key("1"), None);
+        assert_eq!(db.lock().ttl(&key("a")), Some(None));
+        assert!(db.lock().expire(&key("a"), Duration::from_secs(3)));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(db.lock().ttl(&key("a")), Some(Some(Duration::from_secs(2))));
+        assert!(db.lock().expire(&key("a"), Duration::ZERO));
+        assert_eq!(db.lock().ttl(&key("a")), None);
+        assert!(!db.lock().expire(&key("a"), Duration::from_secs(1)));
+        set(&db, key("b"), key("2"), Some(Duration::MAX));
+        assert_eq!(db.lock().ttl(&key("b")), Some(Some(MAX_TTL)));
+        assert!(db.lock().expire(&key("b"), Duration::from_secs(u64::MAX)));
+        assert_eq!(db.lock().ttl(&key("b")), Some(Some(MAX_TTL)));
+    }
+
+    #[test]
+    fn test_typed_access() {
+        let db = Db::new();
+        set(&db, key("a"), key("1"), None);
+        assert_eq!(db.lock().get::<SortedSet>(&key("a")), Err(WrongType));
+        let result = db.lock().update(&key("a"), |set: &mut SortedSet| set.len());
+        assert_eq!(result, Err(WrongType));
+        assert_eq!(db.lock().get::<SortedSet>(&key("b")), Ok(None));
+        let inserted = db
+            .lock()
+            .upsert(&key("b"), |set: &mut SortedSet| set.insert(key("x"), 1.0));
+        assert_eq!(inserted, Ok(true));
+        let removed = db
+            .lock()
+            .update(&key("b"), |set: &mut SortedSet| set.remove(&key("x")));
+        assert_eq!(removed, Ok(Some(true)));
+        assert!(!db
+            .shared
+            .state
+            .lock()
+            .unwrap()
+            .entries
+            .contains_key(&key("b")));
+    }
+
+    #[test]
+    fn test_writes_are_refused_after_a_failed_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = AofConfig::new(dir.path().join("appendonly.aof"), FsyncPolicy::Always);
+        let db = Db::open(Persistence {
+            snapshot_path: None,
+            aof: Some(config.clone()),
+        })
+        .unwrap();
+        let execute = |strings: &[&str]| {
+            let strings = strings.iter().map(|s| Bytes::copy_from_slice(s.as_bytes()));
+            command::execute(
+                &db,
+                Request {
+                    strings: strings.collect(),
+                },
+            )
+        };
+        let aof = db.shared.aof.as_ref().unwrap();
+        let writable = aof.replace_file(std::fs::File::open(&config.path).unwrap());
+        assert_eq!(execute(&["INCR", "n"]).value, Value::Int(1));
+        let refused = execute(&["INCR", "n"]);
+        assert_eq!(refused.status_code, ResponseStatusCode::Err);
+        assert_eq!(execute(&["GET", "n"]).value, Value::String(key("1")));
+        aof.replace_file(writable);
+        aof.retry_pending().unwrap();
+        assert_eq!(execute(&["INCR", "n"]).value, Value::Int(2));
+        let (_, requests) = AppendOnlyFile::open(&config).unwrap();
+        assert_eq!(requests.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_reads_proceed_during_background_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dump.snapshot");
+        let db = Db::open(Persistence {
+            snapshot_path: Some(path.clone()),
+            aof: None,
+        })
+        .unwrap();
+        {
+            let mut keyspace = db.lock();
+            for i in 0..100_000 {
+                let name = Bytes::from(format!("key:{}", i));
+                keyspace.set(name, Object::String(key("old")), None);
+            }
+        }
+        let handle = db.lock().save_in_background().unwrap();
+        let mut reads = 0;
+        while !db.shared.state.lock().unwrap().captures.is_empty() {
+            assert_eq!(get(&db, &key("key:0")), Some(key("old")));
+            reads += 1;
+            if reads == 10 {
+                set(&db, key("key:1"), key("new"), None);
+                assert_eq!(db.lock().del(&[key("key:2")]), 1);
+                set(&db, key("added"), key("new"), None);
+            }
+            tokio::task::yield_now().await;
+        }
+        assert!(reads > 10);
+        handle.await.unwrap();
+        let entries = Snapshotter::new(path).load().unwrap();
+        assert_eq!(entries.len(), 100_000);
+        let value = |name: &str| {
+            entries
+                .iter()
+                .find(|entry| entry.key == name.as_bytes())
+                .map(|entry| entry.object.clone())
+        };
+        assert_eq!(value("key:1"), Some(Object::String(key("old"))));
+        assert_eq!(value("key:2"), Some(Object::String(key("old"))));
+        assert_eq!(value("added"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_restore_skips_expired_entries() {
+        let db = Db::new();
+        let entry = |name, expires_at| SnapshotEntry {
+            key: key(name),
+            object: Object::String(key("value")),
+            expires_at,
+        };
+        let restored = db.lock().restore(vec![
+            entry("expired", Some(SystemTime::now() - Duration::from_secs(1))),
+            entry(
+                "expiring",
+                Some(SystemTime::now() + Duration::from_secs(10)),
+            ),
+            entry("persistent", None),
+        ]);
+        assert_eq!(restored, 2);
+        assert_eq!(get(&db, &key("expired")), None);
+        let ttl = db.lock().ttl(&key("expiring")).unwrap().unwrap();
+        assert!(ttl > Duration::from_secs(9) && ttl <= Duration::from_secs(10));
+        let snapshot = db.lock().snapshot();
+        assert_eq!(snapshot.len(), 2);
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(db.lock().snapshot().len(), 1);
+    }
+}
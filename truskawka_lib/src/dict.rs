@@ -0,0 +1,567 @@
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+
+const MIN_BUCKETS: usize = 4;
+const REHASH_STEPS_PER_OPERATION: usize = 1;
+const MAX_EMPTY_VISITS_PER_STEP: usize = 10;
+
+type Link<K, V> = Option<Box<Node<K, V>>>;
+
+#[derive(Clone)]
+struct Node<K, V> {
+    hash: u64,
+    key: K,
+    value: V,
+    next: Link<K, V>,
+}
+
+#[derive(Clone)]
+struct Table<K, V> {
+    buckets: Vec<Link<K, V>>,
+    len: usize,
+}
+
+impl<K, V> Default for Table<K, V> {
+    fn default() -> Self {
+        Table {
+            buckets: Vec::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<K, V> Table<K, V> {
+    fn with_buckets(size: usize) -> Self {
+        let mut buckets = Vec::with_capacity(size);
+        buckets.resize_with(size, || None);
+        Table { buckets, len: 0 }
+    }
+
+    fn mask(&self) -> u64 {
+        self.buckets.len() as u64 - 1
+    }
+
+    fn bucket(&self, hash: u64) -> usize {
+        (hash & self.mask()) as usize
+    }
+
+    fn get<Q>(&self, hash: u64, key: &Q) -> Option<&Node<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Eq + ?Sized,
+    {
+        if self.len == 0 {
+            return None;
+        }
+        let mut link = self.buckets[self.bucket(hash)].as_deref();
+        while let Some(node) = link {
+            if node.hash == hash && node.key.borrow() == key {
+                return Some(node);
+            }
+            link = node.next.as_deref();
+        }
+        None
+    }
+
+    fn get_mut<Q>(&mut self, hash: u64, key: &Q) -> Option<&mut Node<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Eq + ?Sized,
+    {
+        if self.len == 0 {
+            return None;
+        }
+        let bucket = self.bucket(hash);
+        let mut link = self.buckets[bucket].as_deref_mut();
+        while let Some(node) = link {
+            if node.hash == hash && node.key.borrow() == key {
+                return Some(node);
+            }
+            link = node.next.as_deref_mut();
+        }
+        None
+    }
+
+    fn push(&mut self, mut node: Box<Node<K, V>>) {
+        let bucket = self.bucket(node.hash);
+        node.next = self.buckets[bucket].take();
+        self.buckets[bucket] = Some(node);
+        self.len += 1;
+    }
+
+    fn remove<Q>(&mut self, hash: u64, key: &Q) -> Option<Box<Node<K, V>>>
+    where
+        K: Borrow<Q>,
+        Q: Eq + ?Sized,
+    {
+        if self.len == 0 {
+            return None;
+        }
+        let bucket = self.bucket(hash);
+        let mut link = &mut self.buckets[bucket];
+        while link
+            .as_ref()
+            .is_some_and(|node| node.hash != hash || node.key.borrow() != key)
+        {
+            link = &mut link.as_mut().unwrap().next;
+        }
+        let mut node = link.take()?;
+        *link = node.next.take();
+        self.len -= 1;
+        Some(node)
+    }
+
+    fn retain(&mut self, f: &mut impl FnMut(&K, &mut V) -> bool) {
+        for bucket in self.buckets.iter_mut() {
+            let mut chain = bucket.take();
+            while let Some(mut node) = chain {
+                chain = node.next.take();
+                if f(&node.key, &mut node.value) {
+                    node.next = bucket.take();
+                    *bucket = Some(node);
+                } else {
+                    self.len -= 1;
+                }
+            }
+        }
+    }
+
+    fn visit(&self, bucket: usize, f: &mut impl FnMut(&K, &V)) {
+        let mut link = self.buckets[bucket].as_deref();
+        while let Some(node) = link {
+            f(&node.key, &node.value);
+            link = node.next.as_deref();
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Dict<K, V> {
+    tables: [Table<K, V>; 2],
+    rehash_index: Option<usize>,
+    hasher: RandomState,
+}
+
+impl<K, V> Default for Dict<K, V> {
+    fn default() -> Self {
+        Dict {
+            tables: [Table::default(), Table::default()],
+            rehash_index: None,
+            hasher: RandomState::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq, V> Dict<K, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tables[0].len + self.tables[1].len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_rehashing(&self) -> bool {
+        self.rehash_index.is_some()
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let hash = self.hasher.hash_one(key);
+        self.tables
+            .iter()
+            .find_map(|table| table.get(hash, key))
+            .map(|node| &node.value)
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.rehash(REHASH_STEPS_PER_OPERATION);
+        let hash = self.hasher.hash_one(key);
+        let [old, new] = &mut self.tables;
+        old.get_mut(hash, key)
+            .or_else(|| new.get_mut(hash, key))
+            .map(|node| &mut node.value)
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.get(key).is_some()
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if let Some(current) = self.get_mut(&key) {
+            return Some(std::mem::replace(current, value));
+        }
+        self.resize_if_needed(self.len() + 1);
+        let node = Box::new(Node {
+            hash: self.hasher.hash_one(&key),
+            key,
+            value,
+            next: None,
+        });
+        match self.rehash_index {
+            Some(_) => self.tables[1].push(node),
+            None => self.tables[0].push(node),
+        }
+        None
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.rehash(REHASH_STEPS_PER_OPERATION);
+        let hash = self.hasher.hash_one(key);
+        let [old, new] = &mut self.tables;
+        let node = old.remove(hash, key).or_else(|| new.remove(hash, key))?;
+        self.resize_if_needed(self.len());
+        Some(node.value)
+    }
+
+    pub fn retain(&mut self, mut f: impl FnMut(&K, &mut V) -> bool) {
+        self.tables[0].retain(&mut f);
+        self.tables[1].retain(&mut f);
+        self.resize_if_needed(self.len());
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            buckets: self.tables[0]
+                .buckets
+                .iter()
+                .chain(self.tables[1].buckets.iter()),
+            node: None,
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.iter().map(|(key, _)| key)
+    }
+
+    pub fn scan(&self, mut cursor: u64, mut f: impl FnMut(&K, &V)) -> u64 {
+        if self.is_empty() {
+            return 0;
+        }
+        if !self.is_rehashing() {
+            let table = &self.tables[0];
+            table.visit(table.bucket(cursor), &mut f);
+            return next_cursor(cursor, table.mask());
+        }
+        let (small, large) = match self.tables[0].buckets.len() <= self.tables[1].buckets.len() {
+            true => (&self.tables[0], &self.tables[1]),
+            false => (&self.tables[1], &self.tables[0]),
+        };
+        small.visit(small.bucket(cursor), &mut f);
+        loop {
+            large.visit(large.bucket(cursor), &mut f);
+            cursor = next_cursor(cursor, large.mask());
+            if cursor & (small.mask() ^ large.mask()) == 0 {
+                return cursor;
+            }
+        }
+    }
+
+    pub fn scan_batch(&self, mut cursor: u64, count: usize, mut f: impl FnMut(&K, &V)) -> u64 {
+        let mut visited = 0;
+        let mut buckets = 0;
+        loop {
+            cursor = self.scan(cursor, |key, value| {
+                visited += 1;
+                f(key, value);
+            });
+            buckets += 1;
+            if cursor == 0 || visited >= count || buckets >= count.saturating_mul(10) {
+                return cursor;
+            }
+        }
+    }
+
+    pub fn rehash(&mut self, steps: usize) -> bool {
+        let Some(mut index) = self.rehash_index else {
+            return false;
+        };
+        let [old, new] = &mut self.tables;
+        let mut empty_visits = steps * MAX_EMPTY_VISITS_PER_STEP;
+        for _ in 0..steps {
+            while index < old.buckets.len() && old.buckets[index].is_none() {
+                index += 1;
+                empty_visits -= 1;
+                if empty_visits == 0 {
+                    self.rehash_index = Some(index);
+                    return true;
+                }
+            }
+            let Some(bucket) = old.buckets.get_mut(index) else {
+                break;
+            };
+            let mut chain = bucket.take();
+            while let Some(mut node) = chain {
+                chain = node.next.take();
+                old.len -= 1;
+                new.push(node);
+            }
+            index += 1;
+        }
+        if index < old.buckets.len() {
+            self.rehash_index = Some(index);
+            return true;
+        }
+        log::debug!("Finished rehashing {} entries", new.len);
+        self.tables[0] = std::mem::take(&mut self.tables[1]);
+        self.rehash_index = None;
+        self.resize_if_needed(self.len());
+        self.is_rehashing()
+    }
+
+    fn resize_if_needed(&mut self, len: usize) {
+        if self.is_rehashing() {
+            return;
+        }
+        let buckets = self.tables[0].buckets.len();
+        if len > buckets {
+            self.resize((buckets * 2).max(len.next_power_of_two()).max(MIN_BUCKETS));
+        } else if buckets > MIN_BUCKETS && len * 8 < buckets {
+            self.resize(len.next_power_of_two().max(MIN_BUCKETS));
+        }
+    }
+
+    fn resize(&mut self, size: usize) {
+        if self.tables[0].len == 0 {
+            self.tables[0] = Table::with_buckets(size);
+            return;
+        }
+        log::debug!(
+            "Rehashing {} entries from {} to {} buckets",
+            self.len(),
+            self.tables[0].buckets.len(),
+            size
+        );
+        self.tables[1] = Table::with_buckets(size);
+        self.rehash_index = Some(0);
+    }
+}
+
+fn next_cursor(cursor: u64, mask: u64) -> u64 {
+    (cursor | !mask)
+        .reverse_bits()
+        .wrapping_add(1)
+        .reverse_bits()
+}
+
+impl<K: Hash + Eq, V: PartialEq> PartialEq for Dict<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len()
+            && self
+                .iter()
+                .all(|(key, value)| other.get(key) == Some(value))
+    }
+}
+
+impl<K: Hash + Eq + fmt::Debug, V: fmt::Debug> fmt::Debug for Dict<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<K: Hash + Eq, V> FromIterator<(K, V)> for Dict<K, V> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut dict = Dict::new();
+        for (key, value) in iter {
+            dict.insert(key, value);
+        }
+        dict
+    }
+}
+
+impl<'a, K: Hash + Eq, V> IntoIterator for &'a Dict<K, V> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+type Buckets<'a, K, V> =
+    std::iter::Chain<std::slice::Iter<'a, Link<K, V>>, std::slice::Iter<'a, Link<K, V>>>;
+
+pub struct Iter<'a, K, V> {
+    buckets: Buckets<'a, K, V>,
+    node: Option<&'a Node<K, V>>,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(node) = self.node {
+                self.node = node.next.as_deref();
+                return Some((&node.key, &node.value));
+            }
+            self.node = self.buckets.next()?.as_deref();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn test_insert_get_and_remove() {
+        let mut dict = Dict::new();
+        for i in 0..1000 {
+            assert_eq!(dict.insert(i, i * 2), None);
+        }
+        assert_eq!(dict.insert(7, 0), Some(14));
+        assert_eq!(dict.len(), 1000);
+        assert_eq!(dict.get(&7), Some(&0));
+        assert_eq!(dict.get(&500), Some(&1000));
+        *dict.get_mut(&500).unwrap() += 1;
+        assert_eq!(dict.get(&500), Some(&1001));
+        for i in 0..990 {
+            assert!(dict.remove(&i).is_some());
+        }
+        assert_eq!(dict.remove(&0), None);
+        assert_eq!(dict.len(), 10);
+        while dict.rehash(100) {}
+        assert!(dict.tables[0].buckets.len() <= 16);
+        let mut keys: Vec<_> = dict.keys().copied().collect();
+        keys.sort();
+        assert_eq!(keys, (990..1000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_retain() {
+        let mut dict: Dict<_, _> = (0..100).map(|i| (i, i)).collect();
+        dict.retain(|key, value| {
+            *value += 1;
+            key % 10 == 0
+        });
+        assert_eq!(dict.len(), 10);
+        assert_eq!(dict.get(&50), Some(&51));
+        assert_eq!(dict.get(&51), None);
+    }
+
+    #[test]
+    fn test_scan_visits_every_key() {
+        let dict: Dict<_, _> = (0..1000).map(|i| (i, ())).collect();
+        let mut seen = HashSet::new();
+        let mut cursor = 0;
+        loop {
+            cursor = dict.scan_batch(cursor, 10, |key, _| {
+                seen.insert(*key);
+            });
+            if cursor == 0 {
+                break;
+            }
+        }
+        assert_eq!(seen.len(), 1000);
+    }
+
+    #[test]
+    fn test_scan_survives_resizing() {
+        let mut dict: Dict<_, _> = (0..100).map(|i| (i, ())).collect();
+        let mut seen = HashSet::new();
+        let mut cursor = 0;
+        let mut round = 0;
+        loop {
+            cursor = dict.scan_batch(cursor, 5, |key, _| {
+                seen.insert(*key);
+            });
+            round += 1;
+            match round {
+                3 => (1000..3000).for_each(|i| {
+                    dict.insert(i, ());
+                }),
+                6 => (1000..3000).for_each(|i| {
+                    dict.remove(&i);
+                }),
+                _ => {}
+            }
+            if cursor == 0 {
+                break;
+            }
+        }
+        assert!((0..100).all(|i| seen.contains(&i)));
+    }
+
+    #[test]
+    fn test_growth_rehashes_incrementally() {
+        let mut dict = Dict::new();
+        for i in 0..1024 {
+            dict.insert(i, i);
+        }
+        assert!(!dict.is_rehashing());
+        dict.insert(1024, 1024);
+        assert!(dict.is_rehashing());
+        assert_eq!(dict.tables[1].buckets.len(), 2048);
+        assert!(dict.tables[0].len > 1000);
+        for i in 0..=1024 {
+            assert_eq!(dict.get(&i), Some(&i));
+        }
+        let mut operations = 0;
+        while dict.is_rehashing() {
+            dict.insert(2000 + operations, 0);
+            operations += 1;
+            assert!(dict.tables[1].len <= dict.len());
+        }
+        assert!(operations > 100);
+        assert_eq!(dict.tables[0].buckets.len(), 2048);
+        assert_eq!(dict.tables[1].buckets.len(), 0);
+        assert_eq!(dict.len(), 1025 + operations);
+        for i in 0..=1024 {
+            assert_eq!(dict.remove(&i), Some(i));
+        }
+    }
+
+    #[test]
+    fn test_scan_during_rehash() {
+        for (initial, target) in [(64, 65), (64, 7)] {
+            let mut dict: Dict<_, _> = (0..initial).map(|i| (i, ())).collect();
+            while dict.rehash(100) {}
+            if target > initial {
+                (initial..target).for_each(|i| {
+                    dict.insert(i, ());
+                });
+            } else {
+                (target..initial).for_each(|i| {
+                    dict.remove(&i);
+                });
+            }
+            assert!(dict.is_rehashing());
+            let mut seen = HashSet::new();
+            let mut cursor = 0;
+            loop {
+                cursor = dict.scan(cursor, |key, _| {
+                    seen.insert(*key);
+                });
+                dict.rehash(1);
+                if cursor == 0 {
+                    break;
+                }
+            }
+            assert_eq!(seen.len(), target);
+        }
+    }
+}
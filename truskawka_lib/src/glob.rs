@@ -0,0 +1,124 @@
+pub fn matches(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    let mut star = None;
+    while s < string.len() {
+        if pattern.get(p) == Some(&b'*') {
+            star = Some((p, s));
+            p += 1;
+            continue;
+        }
+        if let Some(len) = pattern
+            .get(p..)
+            .filter(|token| !token.is_empty())
+            .and_then(|token| match_token(token, string[s]))
+        {
+            p += len;
+            s += 1;
+            continue;
+        }
+        match star {
+            Some((star_p, star_s)) => {
+                star = Some((star_p, star_s + 1));
+                p = star_p + 1;
+                s = star_s + 1;
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+fn match_token(pattern: &[u8], c: u8) -> Option<usize> {
+    match pattern[0] {
+        b'?' => Some(1),
+        b'\\' if pattern.len() > 1 => (pattern[1] == c).then_some(2),
+        b'[' => match_class(pattern, c),
+        literal => (literal == c).then_some(1),
+    }
+}
+
+fn match_class(pattern: &[u8], c: u8) -> Option<usize> {
+    let negate = pattern.get(1) == Some(&b'^');
+    let mut i = if negate { 2 } else { 1 };
+    let mut matched = false;
+    loop {
+        match pattern.get(i) {
+            None => return (c == b'[').then_some(1),
+            Some(b']') => break,
+            Some(b'\\') if i + 1 < pattern.len() => {
+                matched |= pattern[i + 1] == c;
+                i += 2;
+            }
+            Some(&start)
+                if pattern.get(i + 1) == Some(&b'-')
+                    && pattern.get(i + 2).is_some_and(|&end| end != b']') =>
+            {
+                let end = pattern[i + 2];
+                matched |= (start.min(end)..=start.max(end)).contains(&c);
+                i += 3;
+            }
+            Some(&other) => {
+                matched |= other == c;
+                i += 1;
+            }
+        }
+    }
+    (matched != negate).then_some(i + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wildcards() {
+        for (pattern, string, expected) in [
+            ("*", "", true),
+            ("*", "anything", true),
+            ("user:*", "user:42", true),
+            ("user:*", "session:42", false),
+            ("*:42", "user:42", true),
+            ("u?er", "user", true),
+            ("u?er", "uer", false),
+            ("a*b*c", "aXXbYYc", true),
+            ("a*b*c", "aXXbYY", false),
+            ("**x", "yyx", true),
+            ("", "", true),
+            ("", "a", false),
+        ] {
+            assert_eq!(
+                matches(pattern.as_bytes(), string.as_bytes()),
+                expected,
+                "{} against {}",
+                pattern,
+                string
+            );
+        }
+    }
+
+    #[test]
+    fn test_classes_and_escapes() {
+        for (pattern, string, expected) in [
+            ("h[ae]llo", "hello", true),
+            ("h[ae]llo", "hillo", false),
+            ("h[^e]llo", "hallo", true),
+            ("h[^e]llo", "hello", false),
+            ("h[a-c]llo", "hbllo", true),
+            ("h[c-a]llo", "hbllo", true),
+            ("h[a-c]llo", "hdllo", false),
+            ("h\\*llo", "h*llo", true),
+            ("h\\*llo", "hello", false),
+            ("[", "[", true),
+            ("a[", "a[", true),
+            ("[\\]]", "]", true),
+        ] {
+            assert_eq!(
+                matches(pattern.as_bytes(), string.as_bytes()),
+                expected,
+                "{} against {}",
+                pattern,
+                string
+            );
+        }
+    }
+}
@@ -1,4 +1,15 @@
-mod protocol;
+pub mod aof;
+pub mod client;
+pub mod command;
+pub mod db;
+pub mod dict;
+pub mod glob;
+pub mod protocol;
+pub mod replication;
+pub mod server;
+pub mod set;
+pub mod snapshot;
+pub mod sorted_set;
 
 #[test]
 fn it_works() {
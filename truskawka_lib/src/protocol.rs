@@ -1,14 +1,14 @@
-use ascii::{AsciiString, FromAsciiError};
+use ascii::{AsAsciiStrError, AsciiStr};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
-use futures::stream::StreamExt;
 use tokio_util::codec::{Decoder, Encoder};
 
-struct Request {
-    strings: Vec<AsciiString>,
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub strings: Vec<Bytes>,
 }
 
 #[derive(thiserror::Error, Debug)]
-enum InvalidRequestError {
+pub enum InvalidRequestError {
     #[error("error with underlying IO operation")]
     IOError {
         #[from]
@@ -17,43 +17,114 @@ enum InvalidRequestError {
     #[error("failed loading string due to bad ascii encoding")]
     BadAsciiEncoding {
         #[from]
-        source: FromAsciiError<BytesMut>,
+        source: AsAsciiStrError,
     },
+    #[error("request {limit} of {value} exceeds the limit of {max}")]
+    LimitExceeded {
+        limit: &'static str,
+        value: usize,
+        max: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestLimits {
+    pub max_strings: usize,
+    pub max_string_len: usize,
+    pub max_frame_len: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        RequestLimits {
+            max_strings: 1024 * 1024,
+            max_string_len: 512 * 1024 * 1024,
+            max_frame_len: 512 * 1024 * 1024,
+        }
+    }
+}
+
+impl RequestLimits {
+    fn check(limit: &'static str, value: usize, max: usize) -> Result<(), InvalidRequestError> {
+        if value > max {
+            return Err(InvalidRequestError::LimitExceeded { limit, value, max });
+        }
+        Ok(())
+    }
 }
 
-struct RequestCodec {}
+#[derive(Default)]
+pub struct RequestCodec {
+    limits: RequestLimits,
+    strict_ascii: bool,
+    n_strings: Option<usize>,
+    string_len: Option<usize>,
+    frame_len: usize,
+    strings: Vec<Bytes>,
+}
 
 impl RequestCodec {
-    fn ready(src: &mut Bytes) -> bool {
-        log::debug!("Checking request frame readiness");
-        if src.remaining() < 4 {
-            return false;
-        }
-        let n_strings = src.get_u32();
-        for _ in 0..n_strings {
-            if src.remaining() < 4 {
-                return false;
-            }
-            let len = src.get_u32() as usize;
-            if src.remaining() < len {
-                return false;
-            }
-            src.advance(len);
+    pub fn new(limits: RequestLimits) -> Self {
+        RequestCodec {
+            limits,
+            ..Default::default()
         }
-        log::debug!("Request frame ready");
-        true
     }
 
-    fn read_frame(src: &mut BytesMut) -> Result<Request, InvalidRequestError> {
-        let n_strings = src.get_u32();
-        let mut strings = Vec::new();
-        for _ in 0..n_strings {
-            let len = src.get_u32();
-            let string = AsciiString::from_ascii(src.split_to(len as usize))
-                .map_err(|e| InvalidRequestError::BadAsciiEncoding { source: e })?;
-            strings.push(string);
+    pub fn strict_ascii(mut self, strict_ascii: bool) -> Self {
+        self.strict_ascii = strict_ascii;
+        self
+    }
+
+    fn read_header(&mut self, src: &mut BytesMut) -> Result<Option<usize>, InvalidRequestError> {
+        if let Some(n_strings) = self.n_strings {
+            return Ok(Some(n_strings));
+        }
+        if src.len() < 4 {
+            return Ok(None);
+        }
+        let n_strings = src.get_u32() as usize;
+        RequestLimits::check("string count", n_strings, self.limits.max_strings)?;
+        log::debug!("Started reading request frame with {} strings", n_strings);
+        self.n_strings = Some(n_strings);
+        self.frame_len = 4;
+        Ok(Some(n_strings))
+    }
+
+    fn read_string_header(
+        &mut self,
+        src: &mut BytesMut,
+    ) -> Result<Option<usize>, InvalidRequestError> {
+        if let Some(len) = self.string_len {
+            return Ok(Some(len));
         }
-        Ok(Request { strings })
+        if src.len() < 4 {
+            return Ok(None);
+        }
+        let len = src.get_u32() as usize;
+        RequestLimits::check("string length", len, self.limits.max_string_len)?;
+        self.frame_len += 4 + len;
+        RequestLimits::check("frame length", self.frame_len, self.limits.max_frame_len)?;
+        self.string_len = Some(len);
+        Ok(Some(len))
+    }
+
+    fn read_string(&mut self, src: &mut BytesMut) -> Result<bool, InvalidRequestError> {
+        let Some(len) = self.read_string_header(src)? else {
+            src.reserve(4);
+            return Ok(false);
+        };
+        if src.len() < len {
+            src.reserve(len - src.len());
+            return Ok(false);
+        }
+        let string = src.split_to(len).freeze();
+        if self.strict_ascii {
+            AsciiStr::from_ascii(&string[..])?;
+        }
+        self.strings.push(string);
+        self.string_len = None;
+        Ok(true)
     }
 }
 
@@ -62,10 +133,31 @@ impl Decoder for RequestCodec {
     type Error = InvalidRequestError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if !Self::ready(&mut src.clone().freeze()) {
+        let Some(n_strings) = self.read_header(src)? else {
             return Ok(None);
+        };
+        while self.strings.len() < n_strings {
+            if !self.read_string(src)? {
+                return Ok(None);
+            }
+        }
+        log::debug!("Request frame ready");
+        self.n_strings = None;
+        Ok(Some(Request {
+            strings: std::mem::take(&mut self.strings),
+        }))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(src)? {
+            Some(request) => Ok(Some(request)),
+            None if src.is_empty() && self.n_strings.is_none() => Ok(None),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "request frame truncated by end of stream",
+            )
+            .into()),
         }
-        Ok(Some(Self::read_frame(src)?))
     }
 }
 
@@ -82,90 +174,644 @@ impl Encoder<Request> for RequestCodec {
         dst.put_u32(item.strings.len() as u32);
         for string in item.strings {
             dst.put_u32(string.len() as u32);
-            dst.put(string.as_ref())
+            dst.put(string)
         }
         Ok(())
     }
 }
 
-enum ResponseStatusCode {
-    Ok,
-    Err,
-    Nx,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseStatusCode {
+    Ok = 0,
+    Err = 1,
+    Nx = 2,
+    WrongType = 3,
+    Busy = 4,
+    Moved = 5,
+}
+
+impl TryFrom<u32> for ResponseStatusCode {
+    type Error = InvalidResponseError;
+
+    fn try_from(code: u32) -> Result<Self, Self::Error> {
+        match code {
+            0 => Ok(ResponseStatusCode::Ok),
+            1 => Ok(ResponseStatusCode::Err),
+            2 => Ok(ResponseStatusCode::Nx),
+            3 => Ok(ResponseStatusCode::WrongType),
+            4 => Ok(ResponseStatusCode::Busy),
+            5 => Ok(ResponseStatusCode::Moved),
+            code => Err(InvalidResponseError::UnknownStatusCode { code }),
+        }
+    }
+}
+
+impl From<ResponseStatusCode> for u32 {
+    fn from(status_code: ResponseStatusCode) -> Self {
+        status_code as u32
+    }
 }
 
-struct Response {
-    status_code: u32,
-    data: AsciiString,
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Nil,
+    Error(Bytes),
+    String(Bytes),
+    Int(i64),
+    Double(f64),
+    Array(Vec<Value>),
 }
 
-struct ResponseCodec {}
+impl Value {
+    const NIL_TAG: u8 = 0;
+    const ERROR_TAG: u8 = 1;
+    const STRING_TAG: u8 = 2;
+    const INT_TAG: u8 = 3;
+    const DOUBLE_TAG: u8 = 4;
+    const ARRAY_TAG: u8 = 5;
+
+    fn encoded_len(&self) -> usize {
+        let header = 1;
+        match self {
+            Value::Nil => header,
+            Value::Error(string) | Value::String(string) => header + 4 + string.len(),
+            Value::Int(_) | Value::Double(_) => header + 8,
+            Value::Array(values) => {
+                header + 4 + values.iter().map(Value::encoded_len).sum::<usize>()
+            }
+        }
+    }
+
+    fn write(&self, dst: &mut BytesMut) {
+        match self {
+            Value::Nil => dst.put_u8(Self::NIL_TAG),
+            Value::Error(string) => {
+                dst.put_u8(Self::ERROR_TAG);
+                dst.put_u32(string.len() as u32);
+                dst.put_slice(string);
+            }
+            Value::String(string) => {
+                dst.put_u8(Self::STRING_TAG);
+                dst.put_u32(string.len() as u32);
+                dst.put_slice(string);
+            }
+            Value::Int(int) => {
+                dst.put_u8(Self::INT_TAG);
+                dst.put_i64(*int);
+            }
+            Value::Double(double) => {
+                dst.put_u8(Self::DOUBLE_TAG);
+                dst.put_f64(*double);
+            }
+            Value::Array(values) => {
+                dst.put_u8(Self::ARRAY_TAG);
+                dst.put_u32(values.len() as u32);
+                for value in values {
+                    value.write(dst);
+                }
+            }
+        }
+    }
+
+    fn read(src: &mut BytesMut) -> Result<Value, InvalidResponseError> {
+        Self::ensure_remaining(src, 1)?;
+        let tag = src.get_u8();
+        match tag {
+            Self::NIL_TAG => Ok(Value::Nil),
+            Self::ERROR_TAG => Ok(Value::Error(Self::read_string(src)?)),
+            Self::STRING_TAG => Ok(Value::String(Self::read_string(src)?)),
+            Self::INT_TAG => {
+                Self::ensure_remaining(src, 8)?;
+                Ok(Value::Int(src.get_i64()))
+            }
+            Self::DOUBLE_TAG => {
+                Self::ensure_remaining(src, 8)?;
+                Ok(Value::Double(src.get_f64()))
+            }
+            Self::ARRAY_TAG => {
+                Self::ensure_remaining(src, 4)?;
+                let n_values = src.get_u32();
+                let mut values = Vec::new();
+                for _ in 0..n_values {
+                    values.push(Self::read(src)?);
+                }
+                Ok(Value::Array(values))
+            }
+            tag => Err(InvalidResponseError::UnknownValueTag { tag }),
+        }
+    }
+
+    fn read_string(src: &mut BytesMut) -> Result<Bytes, InvalidResponseError> {
+        Self::ensure_remaining(src, 4)?;
+        let len = src.get_u32() as usize;
+        Self::ensure_remaining(src, len)?;
+        Ok(src.split_to(len).freeze())
+    }
+
+    fn ensure_remaining(src: &BytesMut, len: usize) -> Result<(), InvalidResponseError> {
+        if src.remaining() < len {
+            return Err(InvalidResponseError::TruncatedValue);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status_code: ResponseStatusCode,
+    pub value: Value,
+}
+
+impl Response {
+    pub fn ok(value: Value) -> Self {
+        Response {
+            status_code: ResponseStatusCode::Ok,
+            value,
+        }
+    }
+
+    pub fn nx() -> Self {
+        Response {
+            status_code: ResponseStatusCode::Nx,
+            value: Value::Nil,
+        }
+    }
+
+    pub fn error(status_code: ResponseStatusCode, message: &str) -> Self {
+        Response {
+            status_code,
+            value: Value::Error(Bytes::copy_from_slice(message.as_bytes())),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ResponseCodec {
+    header: Option<(u32, usize)>,
+}
 
 #[derive(thiserror::Error, Debug)]
-enum InvalidResponseError {
+pub enum InvalidResponseError {
     #[error("error with underlying IO operation")]
     IOError {
         #[from]
         source: std::io::Error,
     },
+    #[error("unknown response status code {code}")]
+    UnknownStatusCode { code: u32 },
+    #[error("unknown response value tag {tag}")]
+    UnknownValueTag { tag: u8 },
+    #[error("response value ends before its declared length")]
+    TruncatedValue,
+    #[error("response payload has {len} trailing bytes after its value")]
+    TrailingBytes { len: usize },
+}
+
+impl ResponseCodec {
+    fn read_header(&mut self, src: &mut BytesMut) -> Option<(u32, usize)> {
+        if self.header.is_none() && src.len() >= 8 {
+            let status_code = src.get_u32();
+            let len = src.get_u32() as usize;
+            log::debug!("Started reading response frame of {} bytes", len);
+            self.header = Some((status_code, len));
+        }
+        self.header
+    }
+
+    fn read_frame(
+        status_code: u32,
+        mut payload: BytesMut,
+    ) -> Result<Response, InvalidResponseError> {
+        let status_code = ResponseStatusCode::try_from(status_code)?;
+        let value = Value::read(&mut payload)?;
+        if !payload.is_empty() {
+            return Err(InvalidResponseError::TrailingBytes { len: payload.len() });
+        }
+        Ok(Response { status_code, value })
+    }
+}
+
+impl Decoder for ResponseCodec {
+    type Item = Response;
+    type Error = InvalidResponseError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let Some((status_code, len)) = self.read_header(src) else {
+            return Ok(None);
+        };
+        if src.len() < len {
+            src.reserve(len - src.len());
+            return Ok(None);
+        }
+        log::debug!("Response frame ready");
+        self.header = None;
+        let payload = src.split_to(len);
+        Ok(Some(Self::read_frame(status_code, payload)?))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(src)? {
+            Some(response) => Ok(Some(response)),
+            None if src.is_empty() && self.header.is_none() => Ok(None),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "response frame truncated by end of stream",
+            )
+            .into()),
+        }
+    }
+}
+
+impl Encoder<Response> for ResponseCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: Response, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let value_len = item.value.encoded_len();
+        let len = ((u32::BITS / 8) * 2) as usize + value_len;
+        dst.reserve(len);
+        dst.put_u32(item.status_code.into());
+        dst.put_u32(value_len as u32);
+        item.value.write(dst);
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::str::FromStr;
-
-    use futures::SinkExt;
+    use futures::{SinkExt, StreamExt};
     use tokio_util::codec::{FramedRead, FramedWrite};
 
     use super::*;
 
     fn example_request_bytes() -> BytesMut {
-        let strings = [
-            AsciiString::from_str("xyz").unwrap(),
-            AsciiString::from_ascii("abcd").unwrap(),
-        ];
+        let strings = [Bytes::from_static(b"xyz"), Bytes::from_static(b"abcd")];
         let mut buffer = BytesMut::with_capacity(4 + 4 + 3 + 4);
         buffer.put_u32(2);
         buffer.put_u32(strings[0].len() as u32);
-        buffer.put(strings[0].as_ref());
+        buffer.put(strings[0].clone());
         buffer.put_u32(strings[1].len() as u32);
-        buffer.put(strings[1].as_ref());
+        buffer.put(strings[1].clone());
         buffer
     }
 
     #[tokio::test]
     async fn test_decoding_correct_request_frame() {
         let buffer = example_request_bytes();
-        let mut stream = FramedRead::new(&buffer[..], RequestCodec {});
+        let mut stream = FramedRead::new(&buffer[..], RequestCodec::default());
         let request = stream.next().await.unwrap().unwrap();
         assert_eq!(request.strings.len(), 2);
-        assert_eq!(request.strings[0].to_string(), "xyz");
-        assert_eq!(request.strings[1].to_string(), "abcd");
+        assert_eq!(&request.strings[0][..], b"xyz");
+        assert_eq!(&request.strings[1][..], b"abcd");
     }
 
     #[tokio::test]
     async fn test_decoding_request_frame_with_excess_bytes() {
         let mut buffer = example_request_bytes();
         buffer.reserve(3);
-        buffer.put([0 as u8; 3].as_slice());
-        let mut stream = FramedRead::new(&buffer[..], RequestCodec {});
+        buffer.put([0u8; 3].as_slice());
+        let mut stream = FramedRead::new(&buffer[..], RequestCodec::default());
         let request = stream.next().await.unwrap().unwrap();
         assert_eq!(request.strings.len(), 2);
-        assert_eq!(request.strings[0].to_string(), "xyz");
-        assert_eq!(request.strings[1].to_string(), "abcd");
+        assert_eq!(&request.strings[0][..], b"xyz");
+        assert_eq!(&request.strings[1][..], b"abcd");
     }
 
     #[tokio::test]
     async fn test_encoding_request_frame() {
         let request = Request {
-            strings: vec![
-                AsciiString::from_str("xyz").unwrap(),
-                AsciiString::from_str("abcd").unwrap(),
-            ],
+            strings: vec![Bytes::from_static(b"xyz"), Bytes::from_static(b"abcd")],
         };
-        let mut sink = FramedWrite::new(Vec::new(), RequestCodec {});
+        let mut sink = FramedWrite::new(Vec::new(), RequestCodec::default());
         sink.send(request).await.unwrap();
         let serialized = sink.into_inner();
         assert_eq!(&serialized[..], example_request_bytes().as_ref());
     }
+
+    #[test]
+    fn test_decoding_request_frame_byte_by_byte() {
+        let buffer = example_request_bytes();
+        let mut codec = RequestCodec::default();
+        let mut src = BytesMut::new();
+        for (i, byte) in buffer.iter().enumerate() {
+            src.put_u8(*byte);
+            let request = codec.decode(&mut src).unwrap();
+            if i + 1 < buffer.len() {
+                assert!(request.is_none());
+            } else {
+                let request = request.unwrap();
+                assert_eq!(&request.strings[0][..], b"xyz");
+                assert_eq!(&request.strings[1][..], b"abcd");
+            }
+        }
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn test_decoding_pipelined_request_frames() {
+        let mut buffer = example_request_bytes();
+        buffer.extend_from_slice(&example_request_bytes());
+        let mut codec = RequestCodec::default();
+        for _ in 0..2 {
+            let request = codec.decode(&mut buffer).unwrap().unwrap();
+            assert_eq!(request.strings.len(), 2);
+        }
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_decoded_strings_share_input_buffer() {
+        let mut buffer = example_request_bytes();
+        let start = buffer.as_ptr() as usize;
+        let end = start + buffer.len();
+        let request = RequestCodec::default()
+            .decode(&mut buffer)
+            .unwrap()
+            .unwrap();
+        for string in request.strings {
+            let address = string.as_ptr() as usize;
+            assert!(start <= address && address < end);
+        }
+    }
+
+    fn binary_request_bytes() -> BytesMut {
+        let mut buffer = BytesMut::new();
+        buffer.put_u32(1);
+        buffer.put_u32(3);
+        buffer.put_slice(&[b'a', 0, 0xff]);
+        buffer
+    }
+
+    #[test]
+    fn test_decoding_binary_request_frame() {
+        let mut buffer = binary_request_bytes();
+        let request = RequestCodec::default()
+            .decode(&mut buffer)
+            .unwrap()
+            .unwrap();
+        assert_eq!(&request.strings[0][..], &[b'a', 0, 0xff]);
+    }
+
+    #[test]
+    fn test_decoding_binary_request_frame_in_strict_ascii_mode() {
+        let mut buffer = binary_request_bytes();
+        assert!(matches!(
+            RequestCodec::default()
+                .strict_ascii(true)
+                .decode(&mut buffer),
+            Err(InvalidRequestError::BadAsciiEncoding { .. })
+        ));
+        let mut buffer = example_request_bytes();
+        let request = RequestCodec::default()
+            .strict_ascii(true)
+            .decode(&mut buffer)
+            .unwrap();
+        assert!(request.is_some());
+    }
+
+    #[test]
+    fn test_decoding_request_frame_within_limits() {
+        let mut buffer = example_request_bytes();
+        let mut codec = RequestCodec::new(RequestLimits {
+            max_strings: 2,
+            max_string_len: 4,
+            max_frame_len: buffer.len(),
+        });
+        let request = codec.decode(&mut buffer).unwrap().unwrap();
+        assert_eq!(request.strings.len(), 2);
+    }
+
+    #[test]
+    fn test_decoding_request_frame_exceeding_limits() {
+        let limits = [
+            (
+                RequestLimits {
+                    max_strings: 1,
+                    ..Default::default()
+                },
+                "string count",
+            ),
+            (
+                RequestLimits {
+                    max_string_len: 3,
+                    ..Default::default()
+                },
+                "string length",
+            ),
+            (
+                RequestLimits {
+                    max_frame_len: 14,
+                    ..Default::default()
+                },
+                "frame length",
+            ),
+        ];
+        for (limits, expected) in limits {
+            let mut buffer = example_request_bytes();
+            let mut codec = RequestCodec::new(limits);
+            match codec.decode(&mut buffer) {
+                Err(InvalidRequestError::LimitExceeded { limit, .. }) => {
+                    assert_eq!(limit, expected)
+                }
+                _ => panic!("expected {} limit to be exceeded", expected),
+            }
+        }
+    }
+
+    #[test]
+    fn test_rejecting_oversized_string_before_it_arrives() {
+        let mut buffer = BytesMut::new();
+        buffer.put_u32(1);
+        buffer.put_u32(u32::MAX);
+        let mut codec = RequestCodec::default();
+        assert!(matches!(
+            codec.decode(&mut buffer),
+            Err(InvalidRequestError::LimitExceeded {
+                limit: "string length",
+                ..
+            })
+        ));
+    }
+
+    fn example_response_bytes() -> BytesMut {
+        let data = Bytes::from_static(b"value");
+        let mut buffer = BytesMut::with_capacity(4 + 4 + 1 + 4 + 5);
+        buffer.put_u32(2);
+        buffer.put_u32(1 + 4 + data.len() as u32);
+        buffer.put_u8(2);
+        buffer.put_u32(data.len() as u32);
+        buffer.put(data.clone());
+        buffer
+    }
+
+    fn example_response_value() -> Value {
+        Value::String(Bytes::from_static(b"value"))
+    }
+
+    #[tokio::test]
+    async fn test_decoding_correct_response_frame() {
+        let buffer = example_response_bytes();
+        let mut stream = FramedRead::new(&buffer[..], ResponseCodec::default());
+        let response = stream.next().await.unwrap().unwrap();
+        assert_eq!(response.status_code, ResponseStatusCode::Nx);
+        assert_eq!(response.value, example_response_value());
+    }
+
+    #[tokio::test]
+    async fn test_decoding_response_frame_with_excess_bytes() {
+        let mut buffer = example_response_bytes();
+        buffer.reserve(3);
+        buffer.put([0u8; 3].as_slice());
+        let mut stream = FramedRead::new(&buffer[..], ResponseCodec::default());
+        let response = stream.next().await.unwrap().unwrap();
+        assert_eq!(response.status_code, ResponseStatusCode::Nx);
+        assert_eq!(response.value, example_response_value());
+    }
+
+    #[test]
+    fn test_decoding_incomplete_response_frame() {
+        let buffer = example_response_bytes();
+        let mut codec = ResponseCodec::default();
+        let mut partial = BytesMut::from(&buffer[..buffer.len() - 1]);
+        assert!(codec.decode(&mut partial).unwrap().is_none());
+        partial.put_u8(buffer[buffer.len() - 1]);
+        let response = codec.decode(&mut partial).unwrap().unwrap();
+        assert_eq!(response.value, example_response_value());
+        assert!(partial.is_empty());
+    }
+
+    #[test]
+    fn test_decoding_response_frame_byte_by_byte() {
+        let buffer = example_response_bytes();
+        let mut codec = ResponseCodec::default();
+        let mut src = BytesMut::new();
+        for (i, byte) in buffer.iter().enumerate() {
+            src.put_u8(*byte);
+            let response = codec.decode(&mut src).unwrap();
+            if i + 1 < buffer.len() {
+                assert!(response.is_none());
+            } else {
+                assert_eq!(response.unwrap().value, example_response_value());
+            }
+        }
+        assert!(src.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_decoding_truncated_response_stream() {
+        let buffer = example_response_bytes();
+        let mut stream = FramedRead::new(&buffer[..buffer.len() - 1], ResponseCodec::default());
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(InvalidResponseError::IOError { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_decoding_response_with_unknown_value_tag() {
+        let mut buffer = BytesMut::new();
+        buffer.put_u32(0);
+        buffer.put_u32(1);
+        buffer.put_u8(42);
+        let mut codec = ResponseCodec::default();
+        assert!(matches!(
+            codec.decode(&mut buffer),
+            Err(InvalidResponseError::UnknownValueTag { tag: 42 })
+        ));
+    }
+
+    #[test]
+    fn test_decoding_response_with_truncated_value() {
+        let mut buffer = BytesMut::new();
+        buffer.put_u32(0);
+        buffer.put_u32(5);
+        buffer.put_u8(3);
+        buffer.put_u32(0);
+        let mut codec = ResponseCodec::default();
+        assert!(matches!(
+            codec.decode(&mut buffer),
+            Err(InvalidResponseError::TruncatedValue)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_encoding_response_frame() {
+        let response = Response {
+            status_code: ResponseStatusCode::Nx,
+            value: example_response_value(),
+        };
+        let mut sink = FramedWrite::new(Vec::new(), ResponseCodec::default());
+        sink.send(response).await.unwrap();
+        let serialized = sink.into_inner();
+        assert_eq!(&serialized[..], example_response_bytes().as_ref());
+    }
+
+    #[tokio::test]
+    async fn test_response_round_trip() {
+        let values = vec![
+            (ResponseStatusCode::Nx, Value::Nil),
+            (
+                ResponseStatusCode::Err,
+                Value::Error(Bytes::from_static(b"bad things")),
+            ),
+            (ResponseStatusCode::Ok, Value::String(Bytes::new())),
+            (ResponseStatusCode::Ok, Value::Int(-42)),
+            (ResponseStatusCode::Ok, Value::Double(3.5)),
+            (
+                ResponseStatusCode::Ok,
+                Value::Array(vec![
+                    Value::String(Bytes::from_static(&[0, 0xff, b'k'])),
+                    Value::Array(vec![Value::Int(1), Value::Nil]),
+                    Value::Array(vec![]),
+                ]),
+            ),
+        ];
+        let mut sink = FramedWrite::new(Vec::new(), ResponseCodec::default());
+        for (status_code, value) in values.iter() {
+            sink.send(Response {
+                status_code: *status_code,
+                value: value.clone(),
+            })
+            .await
+            .unwrap();
+        }
+        let serialized = sink.into_inner();
+        let mut stream = FramedRead::new(&serialized[..], ResponseCodec::default());
+        for (status_code, value) in values {
+            let response = stream.next().await.unwrap().unwrap();
+            assert_eq!(response.status_code, status_code);
+            assert_eq!(response.value, value);
+        }
+        assert!(stream.next().await.is_none());
+    }
+
+    #[test]
+    fn test_status_code_mapping_is_stable() {
+        let codes = [
+            ResponseStatusCode::Ok,
+            ResponseStatusCode::Err,
+            ResponseStatusCode::Nx,
+            ResponseStatusCode::WrongType,
+            ResponseStatusCode::Busy,
+            ResponseStatusCode::Moved,
+        ];
+        for (expected, code) in codes.into_iter().enumerate() {
+            assert_eq!(u32::from(code), expected as u32);
+            assert_eq!(ResponseStatusCode::try_from(expected as u32).unwrap(), code);
+        }
+    }
+
+    #[test]
+    fn test_decoding_response_with_unknown_status_code() {
+        let mut buffer = BytesMut::new();
+        buffer.put_u32(1000);
+        buffer.put_u32(1);
+        buffer.put_u8(0);
+        buffer.put_u32(0);
+        let mut codec = ResponseCodec::default();
+        assert!(matches!(
+            codec.decode(&mut buffer),
+            Err(InvalidResponseError::UnknownStatusCode { code: 1000 })
+        ));
+        assert_eq!(buffer.len(), 4);
+    }
 }
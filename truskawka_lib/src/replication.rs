@@ -0,0 +1,988 @@
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hasher};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::{Bytes, BytesMut};
+use futures::{SinkExt, StreamExt};
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_util::codec::{Encoder, FramedRead, FramedWrite};
+
+use crate::aof::AofError;
+use crate::command;
+use crate::db::{Capture, Db};
+use crate::protocol::{
+    InvalidRequestError, InvalidResponseError, Request, RequestCodec, Response, ResponseCodec,
+    ResponseStatusCode, Value,
+};
+use crate::snapshot::{self, SnapshotError};
+
+const ACK_INTERVAL: Duration = Duration::from_secs(1);
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+pub const DEFAULT_BACKLOG_SIZE: usize = 1024 * 1024;
+pub const DEFAULT_OUTPUT_LIMIT: usize = 256 * 1024 * 1024;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ReplicationError {
+    #[error("error with underlying IO operation")]
+    IOError {
+        #[from]
+        source: std::io::Error,
+    },
+    #[error("peer sent an invalid request")]
+    InvalidRequest {
+        #[from]
+        source: InvalidRequestError,
+    },
+    #[error("leader sent an invalid response")]
+    InvalidResponse {
+        #[from]
+        source: InvalidResponseError,
+    },
+    #[error("leader sent an invalid snapshot")]
+    Snapshot {
+        #[from]
+        source: SnapshotError,
+    },
+    #[error("leader closed the connection")]
+    ConnectionClosed,
+    #[error("unexpected response from leader: {response:?}")]
+    UnexpectedResponse { response: Response },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkStatus {
+    Connecting,
+    Syncing,
+    Connected,
+}
+
+impl LinkStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            LinkStatus::Connecting => "connecting",
+            LinkStatus::Syncing => "syncing",
+            LinkStatus::Connected => "connected",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Role {
+    Leader {
+        replication_id: String,
+        offset: u64,
+        replicas: Vec<ReplicaInfo>,
+    },
+    Replica {
+        leader: String,
+        status: LinkStatus,
+        offset: u64,
+        last_io: Duration,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplicaInfo {
+    pub address: SocketAddr,
+    pub ack_offset: u64,
+    pub lag: u64,
+    pub last_ack: Duration,
+}
+
+impl From<Role> for Value {
+    fn from(role: Role) -> Self {
+        match role {
+            Role::Leader {
+                replication_id,
+                offset,
+                replicas,
+            } => Value::Array(vec![
+                Value::String(Bytes::from_static(b"leader")),
+                Value::Int(offset as i64),
+                Value::Array(
+                    replicas
+                        .into_iter()
+                        .map(|replica| {
+                            Value::Array(vec![
+                                Value::String(Bytes::from(replica.address.to_string())),
+                                Value::Int(replica.ack_offset as i64),
+                                Value::Int(replica.lag as i64),
+                                Value::Int(replica.last_ack.as_millis() as i64),
+                            ])
+                        })
+                        .collect(),
+                ),
+                Value::String(Bytes::from(replication_id)),
+            ]),
+            Role::Replica {
+                leader,
+                status,
+                offset,
+                last_io,
+            } => Value::Array(vec![
+                Value::String(Bytes::from_static(b"replica")),
+                Value::String(Bytes::from(leader)),
+                Value::String(Bytes::from_static(status.as_str().as_bytes())),
+                Value::Int(offset as i64),
+                Value::Int(last_io.as_millis() as i64),
+            ]),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Replication {
+    state: Mutex<State>,
+}
+
+struct State {
+    replication_id: String,
+    offset: u64,
+    backlog: Backlog,
+    leader: Option<LeaderLink>,
+    replicas: Vec<Replica>,
+    next_replica_id: u64,
+    output_limit: usize,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State {
+            replication_id: new_replication_id(),
+            offset: 0,
+            backlog: Backlog::new(DEFAULT_BACKLOG_SIZE),
+            leader: None,
+            replicas: Vec::new(),
+            next_replica_id: 0,
+            output_limit: DEFAULT_OUTPUT_LIMIT,
+        }
+    }
+}
+
+impl State {
+    fn add_replica(&mut self, address: SocketAddr, offset: u64) -> (u64, ReplicaQueue) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let id = self.next_replica_id;
+        self.next_replica_id += 1;
+        self.replicas.push(Replica {
+            id,
+            address,
+            frames: sender,
+            queued: queued.clone(),
+            ack_offset: offset,
+            last_ack: Instant::now(),
+        });
+        let queue = ReplicaQueue {
+            frames: receiver,
+            queued,
+        };
+        (id, queue)
+    }
+}
+
+// Frames waiting to be written to a replica. The leader counts the queued
+// bytes and disconnects the replica once they exceed the output limit.
+struct ReplicaQueue {
+    frames: mpsc::UnboundedReceiver<Bytes>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl ReplicaQueue {
+    async fn recv(&mut self) -> Option<Bytes> {
+        let frame = self.frames.recv().await?;
+        self.queued.fetch_sub(frame.len(), Ordering::AcqRel);
+        Some(frame)
+    }
+}
+
+struct LeaderLink {
+    address: String,
+    replication_id: Option<String>,
+    status: LinkStatus,
+    last_io: Instant,
+}
+
+struct Replica {
+    id: u64,
+    address: SocketAddr,
+    frames: mpsc::UnboundedSender<Bytes>,
+    queued: Arc<AtomicUsize>,
+    ack_offset: u64,
+    last_ack: Instant,
+}
+
+impl Replication {
+    pub fn set_leader(&self, address: String) {
+        let mut state = self.state.lock().unwrap();
+        state.leader = Some(LeaderLink {
+            address,
+            replication_id: None,
+            status: LinkStatus::Connecting,
+            last_io: Instant::now(),
+        });
+        state.replicas.clear();
+    }
+
+    pub fn set_backlog_size(&self, size: usize) {
+        self.state.lock().unwrap().backlog.resize(size);
+    }
+
+    pub fn set_output_limit(&self, limit: usize) {
+        self.state.lock().unwrap().output_limit = limit;
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.state.lock().unwrap().leader.is_some()
+    }
+
+    pub fn role(&self) -> Role {
+        let state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match &state.leader {
+            Some(link) => Role::Replica {
+                leader: link.address.clone(),
+                status: link.status,
+                offset: state.offset,
+                last_io: now - link.last_io,
+            },
+            None => Role::Leader {
+                replication_id: state.replication_id.clone(),
+                offset: state.offset,
+                replicas: state
+                    .replicas
+                    .iter()
+                    .map(|replica| ReplicaInfo {
+                        address: replica.address,
+                        ack_offset: replica.ack_offset,
+                        lag: state.offset.saturating_sub(replica.ack_offset),
+                        last_ack: now - replica.last_ack,
+                    })
+                    .collect(),
+            },
+        }
+    }
+
+    pub fn feed(&self, requests: &[Request]) {
+        let mut state = self.state.lock().unwrap();
+        if state.leader.is_some() {
+            return;
+        }
+        let mut codec = RequestCodec::default();
+        let mut buf = BytesMut::new();
+        for request in requests {
+            codec
+                .encode(request.clone(), &mut buf)
+                .expect("encoding into memory cannot fail");
+        }
+        let frames = buf.freeze();
+        state.offset += frames.len() as u64;
+        state.backlog.push(&frames);
+        let limit = state.output_limit;
+        state.replicas.retain(|replica| {
+            let queued = replica.queued.fetch_add(frames.len(), Ordering::AcqRel) + frames.len();
+            if queued > limit {
+                log::warn!(
+                    "Disconnecting replica {} with {} bytes queued, over the limit of {}",
+                    replica.address,
+                    queued,
+                    limit
+                );
+                return false;
+            }
+            replica.frames.send(frames.clone()).is_ok()
+        });
+    }
+
+    // Called with the keyspace locked, right after the capture has started, so
+    // the replica picks up the stream at the capture's offset.
+    fn add_replica(&self, address: SocketAddr, capture: Capture) -> (u64, Resync, ReplicaQueue) {
+        let mut state = self.state.lock().unwrap();
+        let offset = state.offset;
+        let (id, receiver) = state.add_replica(address, offset);
+        let resync = Resync::Full {
+            replication_id: state.replication_id.clone(),
+            offset,
+            capture,
+        };
+        (id, resync, receiver)
+    }
+
+    fn resume_replica(
+        &self,
+        address: SocketAddr,
+        replication_id: &[u8],
+        offset: u64,
+    ) -> Option<(u64, Resync, ReplicaQueue)> {
+        let mut state = self.state.lock().unwrap();
+        if replication_id != state.replication_id.as_bytes() {
+            return None;
+        }
+        let backlog = state.backlog.since(state.offset, offset)?;
+        let (id, receiver) = state.add_replica(address, offset);
+        Some((id, Resync::Partial { backlog }, receiver))
+    }
+
+    fn remove_replica(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.replicas.retain(|replica| replica.id != id);
+    }
+
+    fn acknowledge(&self, id: u64, offset: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(replica) = state.replicas.iter_mut().find(|replica| replica.id == id) {
+            replica.ack_offset = offset;
+            replica.last_ack = Instant::now();
+        }
+    }
+
+    fn set_link_status(&self, status: LinkStatus) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(link) = &mut state.leader {
+            link.status = status;
+            link.last_io = Instant::now();
+        }
+    }
+
+    fn synced(&self, replication_id: Option<String>, offset: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.offset = offset;
+        if let Some(link) = &mut state.leader {
+            if replication_id.is_some() {
+                link.replication_id = replication_id;
+            }
+            link.status = LinkStatus::Connected;
+            link.last_io = Instant::now();
+        }
+    }
+
+    fn advance(&self, len: u64) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.offset += len;
+        if let Some(link) = &mut state.leader {
+            link.last_io = Instant::now();
+        }
+        state.offset
+    }
+
+    fn offset(&self) -> u64 {
+        self.state.lock().unwrap().offset
+    }
+
+    fn psync_request(&self) -> Request {
+        let state = self.state.lock().unwrap();
+        match state
+            .leader
+            .as_ref()
+            .and_then(|link| link.replication_id.as_ref())
+        {
+            Some(replication_id) => {
+                let offset = state.offset.to_string();
+                request(&[b"PSYNC", replication_id.as_bytes(), offset.as_bytes()])
+            }
+            None => request(&[b"PSYNC", b"?", b"-1"]),
+        }
+    }
+}
+
+struct Backlog {
+    buffer: VecDeque<u8>,
+    capacity: usize,
+}
+
+impl Backlog {
+    fn new(capacity: usize) -> Self {
+        Backlog {
+            buffer: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    fn push(&mut self, frames: &[u8]) {
+        self.buffer.extend(frames);
+        self.trim();
+    }
+
+    fn resize(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.trim();
+    }
+
+    fn since(&self, end_offset: u64, offset: u64) -> Option<Bytes> {
+        let missing = usize::try_from(end_offset.checked_sub(offset)?).ok()?;
+        let start = self.buffer.len().checked_sub(missing)?;
+        Some(
+            self.buffer
+                .range(start..)
+                .copied()
+                .collect::<Vec<_>>()
+                .into(),
+        )
+    }
+
+    fn trim(&mut self) {
+        let excess = self.buffer.len().saturating_sub(self.capacity);
+        self.buffer.drain(..excess);
+    }
+}
+
+fn new_replication_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let mut id = String::new();
+    while id.len() < 40 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(nanos);
+        id.push_str(&format!("{:016x}", hasher.finish()));
+    }
+    id.truncate(40);
+    id
+}
+
+enum Resync {
+    Partial {
+        backlog: Bytes,
+    },
+    Full {
+        replication_id: String,
+        offset: u64,
+        capture: Capture,
+    },
+}
+
+pub fn is_psync_request(request: &Request) -> bool {
+    request
+        .strings
+        .first()
+        .is_some_and(|name| name.eq_ignore_ascii_case(b"PSYNC"))
+}
+
+pub async fn serve_replica(
+    db: Db,
+    address: SocketAddr,
+    request: Request,
+    requests: FramedRead<OwnedReadHalf, RequestCodec>,
+    mut responses: FramedWrite<OwnedWriteHalf, ResponseCodec>,
+) -> Result<(), ReplicationError> {
+    if db.replication().is_replica() {
+        let response = Response::error(ResponseStatusCode::Err, "replicas cannot serve replicas");
+        responses.send(response).await?;
+        return Ok(());
+    }
+    let [_, replication_id, offset] = &request.strings[..] else {
+        let response = Response::error(ResponseStatusCode::Err, "syntax error in 'psync' command");
+        responses.send(response).await?;
+        return Ok(());
+    };
+    let offset = std::str::from_utf8(offset)
+        .ok()
+        .and_then(|offset| offset.parse().ok());
+    let resumed = offset.and_then(|offset| {
+        db.replication()
+            .resume_replica(address, replication_id, offset)
+    });
+    let (id, resync, queue) = match resumed {
+        Some(resumed) => resumed,
+        None => {
+            let mut keyspace = db.lock();
+            let capture = keyspace.capture();
+            db.replication().add_replica(address, capture)
+        }
+    };
+    let result = async {
+        let writer = match resync {
+            Resync::Partial { backlog } => {
+                log::info!(
+                    "Resuming replica {} with {} bytes from the backlog",
+                    address,
+                    backlog.len()
+                );
+                let value = Value::Array(vec![Value::String(Bytes::from_static(b"CONTINUE"))]);
+                responses.send(Response::ok(value)).await?;
+                let mut writer = responses.into_inner();
+                writer.write_all(&backlog).await?;
+                writer
+            }
+            Resync::Full {
+                replication_id,
+                offset,
+                capture,
+            } => {
+                let entries = capture.finish().await;
+                log::info!(
+                    "Starting full sync of {} keys with replica {}",
+                    entries.len(),
+                    address
+                );
+                let payload = tokio::task::spawn_blocking(move || snapshot::encode(&entries))
+                    .await
+                    .map_err(std::io::Error::other)?;
+                let value = Value::Array(vec![
+                    Value::String(Bytes::from_static(b"FULLSYNC")),
+                    Value::String(Bytes::from(replication_id)),
+                    Value::Int(offset as i64),
+                    Value::String(Bytes::from(payload)),
+                ]);
+                responses.send(Response::ok(value)).await?;
+                responses.into_inner()
+            }
+        };
+        stream_to_replica(&db, id, writer, queue, requests).await
+    }
+    .await;
+    db.replication().remove_replica(id);
+    result
+}
+
+async fn stream_to_replica(
+    db: &Db,
+    id: u64,
+    mut writer: OwnedWriteHalf,
+    mut queue: ReplicaQueue,
+    mut requests: FramedRead<OwnedReadHalf, RequestCodec>,
+) -> Result<(), ReplicationError> {
+    loop {
+        tokio::select! {
+            frame = queue.recv() => {
+                let Some(frame) = frame else {
+                    return Ok(());
+                };
+                writer.write_all(&frame).await?;
+            }
+            request = requests.next() => match request {
+                Some(request) => handle_replica_request(db, id, request?),
+                None => return Ok(()),
+            },
+        }
+    }
+}
+
+fn handle_replica_request(db: &Db, id: u64, request: Request) {
+    let offset = match &request.strings[..] {
+        [name, subcommand, offset]
+            if name.eq_ignore_ascii_case(b"REPLCONF")
+                && subcommand.eq_ignore_ascii_case(b"ACK") =>
+        {
+            std::str::from_utf8(offset)
+                .ok()
+                .and_then(|offset| offset.parse().ok())
+        }
+        _ => None,
+    };
+    match offset {
+        Some(offset) => db.replication().acknowledge(id, offset),
+        None => log::warn!("Ignoring unexpected request from replica: {:?}", request),
+    }
+}
+
+pub async fn follow(db: Db, leader: String) {
+    loop {
+        db.replication().set_link_status(LinkStatus::Connecting);
+        match sync_with_leader(&db, &leader).await {
+            Ok(()) => log::warn!("Leader {} closed the replication stream", leader),
+            Err(e) => log::warn!("Replication from {} failed: {}", leader, e),
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn sync_with_leader(db: &Db, leader: &str) -> Result<(), ReplicationError> {
+    let socket = TcpStream::connect(leader).await?;
+    socket.set_nodelay(true)?;
+    let (reader, writer) = socket.into_split();
+    let mut requests = FramedWrite::new(writer, RequestCodec::default());
+    let mut responses = FramedRead::new(reader, ResponseCodec::default());
+    requests.send(db.replication().psync_request()).await?;
+    db.replication().set_link_status(LinkStatus::Syncing);
+    let response = responses
+        .next()
+        .await
+        .ok_or(ReplicationError::ConnectionClosed)??;
+    let values = match response {
+        Response {
+            status_code: ResponseStatusCode::Ok,
+            value: Value::Array(values),
+        } => values,
+        response => return Err(ReplicationError::UnexpectedResponse { response }),
+    };
+    match &values[..] {
+        [Value::String(reply)] if reply.as_ref() == b"CONTINUE" => {
+            let offset = db.replication().offset();
+            db.replication().synced(None, offset);
+            log::info!("Resumed replication from {} at offset {}", leader, offset);
+        }
+        [Value::String(reply), Value::String(replication_id), Value::Int(offset), Value::String(payload)]
+            if reply.as_ref() == b"FULLSYNC" =>
+        {
+            let replication_id = String::from_utf8_lossy(replication_id).into_owned();
+            let (offset, payload) = (*offset as u64, payload.clone());
+            let entries = tokio::task::spawn_blocking(move || snapshot::decode(&payload))
+                .await
+                .map_err(std::io::Error::other)??;
+            let mut keyspace = db.lock();
+            keyspace.flush();
+            let loaded = keyspace.restore(entries);
+            db.replication().synced(Some(replication_id), offset);
+            log::info!("Loaded {} keys from leader {}", loaded, leader);
+            match keyspace.rewrite_append_only_file() {
+                Ok(_) | Err(AofError::Disabled) => {}
+                Err(e) => log::error!("Failed to rewrite the append-only file: {:?}", e),
+            }
+        }
+        _ => {
+            let response = Response::ok(Value::Array(values));
+            return Err(ReplicationError::UnexpectedResponse { response });
+        }
+    }
+    let mut stream = responses.map_decoder(|_| RequestCodec::default());
+    let mut ack = tokio::time::interval(ACK_INTERVAL);
+    loop {
+        tokio::select! {
+            request = stream.next() => {
+                let Some(request) = request else {
+                    return Ok(());
+                };
+                let request = request?;
+                let len = encoded_len(&request);
+                let response = command::replicate(db, request);
+                if response.status_code != ResponseStatusCode::Ok {
+                    log::debug!("Replicated request failed: {:?}", response);
+                }
+                db.replication().advance(len);
+            }
+            _ = ack.tick() => {
+                let offset = db.replication().offset().to_string();
+                requests.send(request(&[b"REPLCONF", b"ACK", offset.as_bytes()])).await?;
+            }
+        }
+    }
+}
+
+fn encoded_len(request: &Request) -> u64 {
+    4 + request
+        .strings
+        .iter()
+        .map(|string| 4 + string.len() as u64)
+        .sum::<u64>()
+}
+
+fn request(strings: &[&[u8]]) -> Request {
+    Request {
+        strings: strings
+            .iter()
+            .map(|string| Bytes::copy_from_slice(string))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::client::Client;
+    use crate::server::{self, Config};
+
+    async fn start_server(db: Db, replica_of: Option<SocketAddr>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let config = Config {
+            replica_of: replica_of.map(|address| address.to_string()),
+            ..Default::default()
+        };
+        tokio::spawn(server::run(listener, db, config));
+        address
+    }
+
+    fn strings(strings: &[&str]) -> Vec<Bytes> {
+        strings
+            .iter()
+            .map(|string| Bytes::copy_from_slice(string.as_bytes()))
+            .collect()
+    }
+
+    async fn eventually(mut condition: impl FnMut() -> bool) {
+        for _ in 0..500 {
+            if condition() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("condition was not met in time");
+    }
+
+    fn get(db: &Db, key: &str) -> Response {
+        command::execute(db, request(&[b"GET", key.as_bytes()]))
+    }
+
+    #[tokio::test]
+    async fn test_replica_receives_snapshot_and_stream() {
+        let leader = Db::new();
+        let leader_address = start_server(leader.clone(), None).await;
+        let mut client = Client::connect(leader_address).await.unwrap();
+        client
+            .execute(strings(&["SET", "before", "1", "EX", "100"]))
+            .await
+            .unwrap();
+        let replica = Db::new();
+        start_server(replica.clone(), Some(leader_address)).await;
+        eventually(|| get(&replica, "before").value == Value::String(Bytes::from_static(b"1")))
+            .await;
+        client
+            .execute(strings(&["RPUSH", "after", "x", "y"]))
+            .await
+            .unwrap();
+        client
+            .execute(strings(&["EXPIRE", "after", "50"]))
+            .await
+            .unwrap();
+        eventually(|| {
+            command::execute(&replica, request(&[b"TTL", b"after"])).value == Value::Int(50)
+        })
+        .await;
+        assert_eq!(
+            command::execute(&replica, request(&[b"LLEN", b"after"])).value,
+            Value::Int(2)
+        );
+        let Role::Leader { offset, .. } = leader.replication().role() else {
+            panic!("expected the leader role");
+        };
+        assert!(offset > 0);
+        eventually(|| {
+            matches!(
+                replica.replication().role(),
+                Role::Replica { status: LinkStatus::Connected, offset: replica_offset, .. }
+                    if replica_offset == offset
+            )
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_replica_rejects_writes() {
+        let leader_address = start_server(Db::new(), None).await;
+        let replica = Db::new();
+        let replica_address = start_server(replica.clone(), Some(leader_address)).await;
+        let mut client = Client::connect(replica_address).await.unwrap();
+        let response = client.execute(strings(&["SET", "a", "1"])).await.unwrap();
+        assert_eq!(
+            response,
+            Response::error(
+                ResponseStatusCode::Err,
+                "writes are not allowed on a read-only replica"
+            )
+        );
+        let response = client.execute(strings(&["GET", "a"])).await.unwrap();
+        assert_eq!(response.status_code, ResponseStatusCode::Nx);
+        assert_eq!(
+            command::replicate(&replica, request(&[b"SET", b"a", b"1"])).status_code,
+            ResponseStatusCode::Ok
+        );
+    }
+
+    #[tokio::test]
+    async fn test_leader_reports_replica_lag() {
+        let leader = Db::new();
+        let leader_address = start_server(leader.clone(), None).await;
+        start_server(Db::new(), Some(leader_address)).await;
+        eventually(|| matches!(leader.replication().role(), Role::Leader { replicas, .. } if replicas.len() == 1))
+            .await;
+        command::execute(&leader, request(&[b"SET", b"a", b"1"]));
+        let Role::Leader {
+            offset, replicas, ..
+        } = leader.replication().role()
+        else {
+            panic!("expected the leader role");
+        };
+        assert_eq!(replicas[0].lag, offset - replicas[0].ack_offset);
+        let Value::Array(role) = command::execute(&leader, request(&[b"ROLE"])).value else {
+            panic!("expected an array");
+        };
+        assert_eq!(role[0], Value::String(Bytes::from_static(b"leader")));
+        assert_eq!(role[1], Value::Int(offset as i64));
+    }
+
+    async fn resync(replica: &Db, leader: SocketAddr) -> tokio::task::JoinHandle<()> {
+        let db = replica.clone();
+        let handle = tokio::spawn(async move {
+            let _ = sync_with_leader(&db, &leader.to_string()).await;
+        });
+        eventually(|| {
+            matches!(
+                replica.replication().role(),
+                Role::Replica {
+                    status: LinkStatus::Connected,
+                    ..
+                }
+            )
+        })
+        .await;
+        handle
+    }
+
+    async fn disconnect(replica: &Db, handle: tokio::task::JoinHandle<()>) {
+        handle.abort();
+        let _ = handle.await;
+        replica
+            .replication()
+            .set_link_status(LinkStatus::Connecting);
+    }
+
+    #[tokio::test]
+    async fn test_replica_resumes_from_backlog() {
+        let leader = Db::new();
+        let leader_address = start_server(leader.clone(), None).await;
+        command::execute(&leader, request(&[b"SET", b"a", b"1"]));
+        let replica = Db::new();
+        replica.replication().set_leader(leader_address.to_string());
+        let handle = resync(&replica, leader_address).await;
+        assert_eq!(
+            get(&replica, "a").value,
+            Value::String(Bytes::from_static(b"1"))
+        );
+        disconnect(&replica, handle).await;
+        command::replicate(&replica, request(&[b"SET", b"local", b"1"]));
+        command::execute(&leader, request(&[b"SET", b"b", b"2"]));
+        let _handle = resync(&replica, leader_address).await;
+        eventually(|| get(&replica, "b").value == Value::String(Bytes::from_static(b"2"))).await;
+        assert_eq!(get(&replica, "local").status_code, ResponseStatusCode::Ok);
+        let Role::Leader { offset, .. } = leader.replication().role() else {
+            panic!("expected the leader role");
+        };
+        assert!(matches!(
+            replica.replication().role(),
+            Role::Replica { offset: replica_offset, .. } if replica_offset == offset
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_replica_falls_back_to_full_sync() {
+        let leader = Db::new();
+        let leader_address = start_server(leader.clone(), None).await;
+        let replica = Db::new();
+        replica.replication().set_leader(leader_address.to_string());
+        let handle = resync(&replica, leader_address).await;
+        leader.replication().set_backlog_size(16);
+        disconnect(&replica, handle).await;
+        command::replicate(&replica, request(&[b"SET", b"local", b"1"]));
+        command::execute(&leader, request(&[b"SET", b"overwrites", b"the backlog"]));
+        let _handle = resync(&replica, leader_address).await;
+        eventually(|| {
+            get(&replica, "overwrites").value == Value::String(Bytes::from_static(b"the backlog"))
+        })
+        .await;
+        assert_eq!(get(&replica, "local").status_code, ResponseStatusCode::Nx);
+    }
+
+    #[tokio::test]
+    async fn test_full_sync_starts_at_the_capture_offset() {
+        let db = Db::new();
+        command::execute(&db, request(&[b"SET", b"a", b"1"]));
+        let address = "127.0.0.1:1".parse().unwrap();
+        let (_, resync, mut queue) = {
+            let mut keyspace = db.lock();
+            let capture = keyspace.capture();
+            db.replication().add_replica(address, capture)
+        };
+        let later = request(&[b"SET", b"b", b"2"]);
+        command::execute(&db, later.clone());
+        let Resync::Full {
+            offset, capture, ..
+        } = resync
+        else {
+            panic!("expected a full sync");
+        };
+        let entries = capture.finish().await;
+        let keys: Vec<_> = entries.iter().map(|entry| entry.key.clone()).collect();
+        assert_eq!(keys, vec![Bytes::from_static(b"a")]);
+        assert_eq!(offset, encoded_len(&request(&[b"SET", b"a", b"1"])));
+        let frame = queue.recv().await.unwrap();
+        assert_eq!(frame.len() as u64, encoded_len(&later));
+    }
+
+    #[tokio::test]
+    async fn test_replicas_that_fall_behind_are_disconnected() {
+        let db = Db::new();
+        let replication = db.replication();
+        replication.set_output_limit(100);
+        let address = "127.0.0.1:1".parse().unwrap();
+        let capture = db.lock().capture();
+        let (_, _, mut queue) = replication.add_replica(address, capture);
+        let incr = || request(&[b"INCR", b"a"]);
+        for _ in 0..5 {
+            replication.feed(&[incr()]);
+        }
+        for _ in 0..2 {
+            assert!(queue.recv().await.is_some());
+        }
+        replication.feed(&[incr(), incr()]);
+        assert_eq!(replication.state.lock().unwrap().replicas.len(), 1);
+        replication.feed(&[incr()]);
+        assert!(replication.state.lock().unwrap().replicas.is_empty());
+        for _ in 0..4 {
+            assert!(queue.recv().await.is_some());
+        }
+        assert!(queue.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_replicas_that_keep_up_stay_connected_past_the_backlog() {
+        let db = Db::new();
+        let replication = db.replication();
+        replication.set_backlog_size(64);
+        let address = "127.0.0.1:1".parse().unwrap();
+        let capture = db.lock().capture();
+        let (_, _, mut queue) = replication.add_replica(address, capture);
+        for _ in 0..10 {
+            replication.feed(&[request(&[b"INCR", b"a"])]);
+            assert!(queue.recv().await.is_some());
+        }
+        let Role::Leader { replicas, .. } = replication.role() else {
+            panic!("expected the leader role");
+        };
+        assert_eq!(replicas.len(), 1);
+        assert_eq!(replicas[0].ack_offset, 0);
+    }
+
+    #[test]
+    fn test_backlog() {
+        let mut backlog = Backlog::new(8);
+        backlog.push(b"abcdef");
+        assert_eq!(backlog.since(6, 6), Some(Bytes::new()));
+        assert_eq!(backlog.since(6, 2), Some(Bytes::from_static(b"cdef")));
+        backlog.push(b"ghij");
+        assert_eq!(backlog.since(10, 2), Some(Bytes::from_static(b"cdefghij")));
+        assert_eq!(backlog.since(10, 1), None);
+        assert_eq!(backlog.since(10, 11), None);
+        backlog.resize(2);
+        assert_eq!(backlog.since(10, 8), Some(Bytes::from_static(b"ij")));
+        assert_eq!(backlog.since(10, 7), None);
+    }
+
+    #[test]
+    fn test_replication_ids_are_unique() {
+        let (first, second) = (new_replication_id(), new_replication_id());
+        assert_eq!(first.len(), 40);
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn test_encoded_len_matches_codec() {
+        let request = request(&[b"SET", b"key", b"value"]);
+        let mut buf = BytesMut::new();
+        RequestCodec::default()
+            .encode(request.clone(), &mut buf)
+            .unwrap();
+        assert_eq!(encoded_len(&request), buf.len() as u64);
+    }
+}
@@ -0,0 +1,253 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures::{FutureExt, SinkExt, StreamExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+use crate::command;
+use crate::db::Db;
+use crate::protocol::{
+    InvalidRequestError, RequestCodec, RequestLimits, Response, ResponseCodec, ResponseStatusCode,
+};
+use crate::replication::{self, ReplicationError};
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConnectionError {
+    #[error("error with underlying IO operation")]
+    IOError {
+        #[from]
+        source: std::io::Error,
+    },
+    #[error("client sent an invalid request")]
+    InvalidRequest {
+        #[from]
+        source: InvalidRequestError,
+    },
+    #[error("replication stream failed")]
+    Replication {
+        #[from]
+        source: ReplicationError,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub request_limits: RequestLimits,
+    pub strict_ascii: bool,
+    pub replica_of: Option<String>,
+    pub replication_backlog_size: usize,
+    pub replication_output_limit: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            request_limits: RequestLimits::default(),
+            strict_ascii: false,
+            replica_of: None,
+            replication_backlog_size: replication::DEFAULT_BACKLOG_SIZE,
+            replication_output_limit: replication::DEFAULT_OUTPUT_LIMIT,
+        }
+    }
+}
+
+pub async fn run(listener: TcpListener, db: Db, config: Config) {
+    tokio::spawn(db.clone().purge_expired_keys());
+    tokio::spawn(db.clone().sync_append_only_file());
+    db.replication()
+        .set_backlog_size(config.replication_backlog_size);
+    db.replication()
+        .set_output_limit(config.replication_output_limit);
+    if let Some(leader) = &config.replica_of {
+        log::info!("Replicating from {}", leader);
+        db.replication().set_leader(leader.clone());
+        tokio::spawn(replication::follow(db.clone(), leader.clone()));
+    }
+    loop {
+        let (socket, address) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                log::error!("Failed accepting connection: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        log::info!("Accepted connection from {}", address);
+        let db = db.clone();
+        let config = config.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(socket, address, db, config).await {
+                log::warn!("Connection with {} failed: {}", address, e);
+            }
+            log::info!("Closed connection from {}", address);
+        });
+    }
+}
+
+async fn handle_connection(
+    socket: TcpStream,
+    address: SocketAddr,
+    db: Db,
+    config: Config,
+) -> Result<(), ConnectionError> {
+    let (reader, writer) = socket.into_split();
+    let codec = RequestCodec::new(config.request_limits).strict_ascii(config.strict_ascii);
+    let mut requests = FramedRead::new(reader, codec);
+    let mut responses = FramedWrite::new(writer, ResponseCodec::default());
+    while let Some(request) = requests.next().await {
+        let mut next = Some(request);
+        while let Some(request) = next.take() {
+            let request = match request {
+                Ok(request) => request,
+                Err(e) => {
+                    let response = Response::error(ResponseStatusCode::Err, &e.to_string());
+                    let _ = responses.send(response).await;
+                    return Err(e.into());
+                }
+            };
+            log::debug!("Received request from {}: {:?}", address, request);
+            if replication::is_psync_request(&request) {
+                responses.flush().await?;
+                log::info!("Connection from {} became a replica", address);
+                replication::serve_replica(db, address, request, requests, responses).await?;
+                return Ok(());
+            }
+            responses.feed(command::execute(&db, request)).await?;
+            next = requests.next().now_or_never().flatten();
+        }
+        responses.flush().await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Bytes, BytesMut};
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpStream;
+    use tokio_util::codec::Encoder;
+
+    use super::*;
+    use crate::protocol::{Request, Value};
+
+    async fn start_server() -> SocketAddr {
+        start_server_with_config(Config::default()).await
+    }
+
+    async fn start_server_with_config(config: Config) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(run(listener, Db::new(), config));
+        address
+    }
+
+    #[tokio::test]
+    async fn test_server_executes_commands() {
+        let address = start_server().await;
+        let (reader, writer) = TcpStream::connect(address).await.unwrap().into_split();
+        let mut requests = FramedWrite::new(writer, RequestCodec::default());
+        let mut responses = FramedRead::new(reader, ResponseCodec::default());
+        for strings in [&["SET", "key", "value"][..], &["GET", "key"]] {
+            requests
+                .send(Request {
+                    strings: strings
+                        .iter()
+                        .map(|string| Bytes::copy_from_slice(string.as_bytes()))
+                        .collect(),
+                })
+                .await
+                .unwrap();
+        }
+        let response = responses.next().await.unwrap().unwrap();
+        assert_eq!(response.status_code, ResponseStatusCode::Ok);
+        let response = responses.next().await.unwrap().unwrap();
+        assert_eq!(response.value, Value::String(Bytes::from_static(b"value")));
+    }
+
+    #[tokio::test]
+    async fn test_server_responds_to_unknown_commands() {
+        let address = start_server().await;
+        let (reader, writer) = TcpStream::connect(address).await.unwrap().into_split();
+        let mut requests = FramedWrite::new(writer, RequestCodec::default());
+        let mut responses = FramedRead::new(reader, ResponseCodec::default());
+        requests
+            .send(Request {
+                strings: vec![Bytes::from_static(b"NOPE")],
+            })
+            .await
+            .unwrap();
+        let response = responses.next().await.unwrap().unwrap();
+        assert_eq!(response.status_code, ResponseStatusCode::Err);
+        assert_eq!(
+            response.value,
+            Value::Error(Bytes::from_static(b"unknown command 'NOPE'"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_server_serves_connections_concurrently() {
+        let address = start_server().await;
+        let idle = TcpStream::connect(address).await.unwrap();
+        let (reader, writer) = TcpStream::connect(address).await.unwrap().into_split();
+        let mut requests = FramedWrite::new(writer, RequestCodec::default());
+        let mut responses = FramedRead::new(reader, ResponseCodec::default());
+        requests.send(Request { strings: vec![] }).await.unwrap();
+        let response = responses.next().await.unwrap().unwrap();
+        assert_eq!(response.status_code, ResponseStatusCode::Err);
+        drop(idle);
+    }
+
+    #[tokio::test]
+    async fn test_server_closes_connection_exceeding_limits() {
+        let address = start_server_with_config(Config {
+            request_limits: RequestLimits {
+                max_strings: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .await;
+        let (reader, writer) = TcpStream::connect(address).await.unwrap().into_split();
+        let mut requests = FramedWrite::new(writer, RequestCodec::default());
+        let mut responses = FramedRead::new(reader, ResponseCodec::default());
+        requests
+            .send(Request {
+                strings: ["DEL", "a", "b"]
+                    .iter()
+                    .map(|string| Bytes::copy_from_slice(string.as_bytes()))
+                    .collect(),
+            })
+            .await
+            .unwrap();
+        let response = responses.next().await.unwrap().unwrap();
+        assert_eq!(response.status_code, ResponseStatusCode::Err);
+        assert!(responses.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_server_answers_pipelined_requests_in_order() {
+        let address = start_server().await;
+        let mut socket = TcpStream::connect(address).await.unwrap();
+        let mut buffer = BytesMut::new();
+        let mut codec = RequestCodec::default();
+        for i in 0..100 {
+            let strings = vec![
+                Bytes::from_static(b"SET"),
+                Bytes::from(format!("key{}", i)),
+                Bytes::from(i.to_string()),
+            ];
+            codec.encode(Request { strings }, &mut buffer).unwrap();
+            let strings = vec![Bytes::from_static(b"GET"), Bytes::from(format!("key{}", i))];
+            codec.encode(Request { strings }, &mut buffer).unwrap();
+        }
+        socket.write_all(&buffer).await.unwrap();
+        let mut responses = FramedRead::new(socket, ResponseCodec::default());
+        for i in 0..100 {
+            let response = responses.next().await.unwrap().unwrap();
+            assert_eq!(response.status_code, ResponseStatusCode::Ok);
+            let response = responses.next().await.unwrap().unwrap();
+            assert_eq!(response.value, Value::String(Bytes::from(i.to_string())));
+        }
+    }
+}
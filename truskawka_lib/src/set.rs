@@ -0,0 +1,307 @@
+use bytes::Bytes;
+
+use crate::dict::Dict;
+
+const MAX_INTSET_ENTRIES: usize = 512;
+
+#[derive(Debug, Clone)]
+pub enum Set {
+    Ints(IntSet),
+    Members(Dict<Bytes, ()>),
+}
+
+impl Default for Set {
+    fn default() -> Self {
+        Set::Ints(IntSet::default())
+    }
+}
+
+impl PartialEq for Set {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.members().iter().all(|member| other.contains(member))
+    }
+}
+
+impl Set {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Set::Ints(ints) => ints.len(),
+            Set::Members(members) => members.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, member: &Bytes) -> bool {
+        match self {
+            Set::Ints(ints) => parse_int(member).is_some_and(|value| ints.contains(value)),
+            Set::Members(members) => members.contains_key(member),
+        }
+    }
+
+    pub fn insert(&mut self, member: Bytes) -> bool {
+        if let Set::Ints(ints) = self {
+            match parse_int(&member) {
+                Some(value) if ints.contains(value) => return false,
+                Some(value) if ints.len() < MAX_INTSET_ENTRIES => return ints.insert(value),
+                _ => self.convert(),
+            }
+        }
+        match self {
+            Set::Members(members) => members.insert(member, ()).is_none(),
+            Set::Ints(_) => unreachable!("set was converted to a hash set"),
+        }
+    }
+
+    pub fn remove(&mut self, member: &Bytes) -> bool {
+        match self {
+            Set::Ints(ints) => parse_int(member).is_some_and(|value| ints.remove(value)),
+            Set::Members(members) => members.remove(member).is_some(),
+        }
+    }
+
+    pub fn retain(&mut self, mut f: impl FnMut(&Bytes) -> bool) {
+        match self {
+            Set::Ints(ints) => ints.retain(|value| f(&format_int(value))),
+            Set::Members(members) => members.retain(|member, _| f(member)),
+        }
+    }
+
+    pub fn members(&self) -> Vec<Bytes> {
+        match self {
+            Set::Ints(ints) => ints.iter().map(format_int).collect(),
+            Set::Members(members) => members.keys().cloned().collect(),
+        }
+    }
+
+    pub fn scan(&self, cursor: u64, count: usize, mut f: impl FnMut(Bytes)) -> u64 {
+        match self {
+            Set::Ints(ints) => {
+                ints.iter().map(format_int).for_each(f);
+                0
+            }
+            Set::Members(members) => {
+                members.scan_batch(cursor, count, |member, _| f(member.clone()))
+            }
+        }
+    }
+
+    fn convert(&mut self) {
+        log::debug!("Converting set of {} integers to a hash set", self.len());
+        *self = Set::Members(
+            self.members()
+                .into_iter()
+                .map(|member| (member, ()))
+                .collect(),
+        );
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IntSet {
+    width: usize,
+    data: Vec<u8>,
+}
+
+impl Default for IntSet {
+    fn default() -> Self {
+        IntSet {
+            width: 2,
+            data: Vec::new(),
+        }
+    }
+}
+
+impl IntSet {
+    pub fn len(&self) -> usize {
+        self.data.len() / self.width
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn contains(&self, value: i64) -> bool {
+        self.search(value).is_ok()
+    }
+
+    pub fn insert(&mut self, value: i64) -> bool {
+        let width = width_of(value);
+        if width > self.width {
+            self.upgrade(width);
+        }
+        match self.search(value) {
+            Ok(_) => false,
+            Err(index) => {
+                let offset = index * self.width;
+                let bytes = value.to_le_bytes();
+                self.data
+                    .splice(offset..offset, bytes[..self.width].iter().copied());
+                true
+            }
+        }
+    }
+
+    pub fn remove(&mut self, value: i64) -> bool {
+        match self.search(value) {
+            Ok(index) => {
+                let offset = index * self.width;
+                self.data.drain(offset..offset + self.width);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    pub fn retain(&mut self, mut f: impl FnMut(i64) -> bool) {
+        let values: Vec<_> = self.iter().filter(|value| f(*value)).collect();
+        self.data.clear();
+        for value in values {
+            self.push(value);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = i64> + '_ {
+        (0..self.len()).map(|index| self.get(index))
+    }
+
+    fn get(&self, index: usize) -> i64 {
+        let bytes = &self.data[index * self.width..(index + 1) * self.width];
+        match self.width {
+            2 => i16::from_le_bytes(bytes.try_into().unwrap()) as i64,
+            4 => i32::from_le_bytes(bytes.try_into().unwrap()) as i64,
+            _ => i64::from_le_bytes(bytes.try_into().unwrap()),
+        }
+    }
+
+    fn push(&mut self, value: i64) {
+        self.data
+            .extend_from_slice(&value.to_le_bytes()[..self.width]);
+    }
+
+    fn search(&self, value: i64) -> Result<usize, usize> {
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let middle = (low + high) / 2;
+            match self.get(middle).cmp(&value) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => return Ok(middle),
+            }
+        }
+        Err(low)
+    }
+
+    fn upgrade(&mut self, width: usize) {
+        let values: Vec<_> = self.iter().collect();
+        self.width = width;
+        self.data = Vec::with_capacity(values.len() * width);
+        for value in values {
+            self.push(value);
+        }
+    }
+}
+
+fn width_of(value: i64) -> usize {
+    if i16::try_from(value).is_ok() {
+        2
+    } else if i32::try_from(value).is_ok() {
+        4
+    } else {
+        8
+    }
+}
+
+fn parse_int(member: &Bytes) -> Option<i64> {
+    let value: i64 = std::str::from_utf8(member).ok()?.parse().ok()?;
+    (format_int(value) == member).then_some(value)
+}
+
+fn format_int(value: i64) -> Bytes {
+    Bytes::from(value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(member: &str) -> Bytes {
+        Bytes::copy_from_slice(member.as_bytes())
+    }
+
+    #[test]
+    fn test_small_numeric_sets_use_intset() {
+        let mut set = Set::new();
+        assert!(set.insert(member("3")));
+        assert!(set.insert(member("-1")));
+        assert!(set.insert(member("100000")));
+        assert!(set.insert(member("9223372036854775807")));
+        assert!(!set.insert(member("3")));
+        let Set::Ints(ints) = &set else {
+            panic!("expected an intset");
+        };
+        assert_eq!(ints.width, 8);
+        assert_eq!(
+            ints.iter().collect::<Vec<_>>(),
+            vec![-1, 3, 100000, i64::MAX]
+        );
+        assert!(set.contains(&member("100000")));
+        assert!(!set.contains(&member("03")));
+        assert!(set.remove(&member("-1")));
+        assert!(!set.remove(&member("-1")));
+        assert_eq!(set.len(), 3);
+    }
+
+    #[test]
+    fn test_non_numeric_members_convert_to_hash_set() {
+        let mut set = Set::new();
+        set.insert(member("1"));
+        set.insert(member("2"));
+        assert!(set.insert(member("02")));
+        assert!(matches!(set, Set::Members(_)));
+        assert_eq!(set.len(), 3);
+        assert!(set.contains(&member("1")));
+        assert!(set.contains(&member("02")));
+    }
+
+    #[test]
+    fn test_large_numeric_sets_convert_to_hash_set() {
+        let mut set = Set::new();
+        for i in 0..MAX_INTSET_ENTRIES {
+            set.insert(format_int(i as i64));
+        }
+        assert!(matches!(set, Set::Ints(_)));
+        set.insert(member("-5"));
+        assert!(matches!(set, Set::Members(_)));
+        assert_eq!(set.len(), MAX_INTSET_ENTRIES + 1);
+    }
+
+    #[test]
+    fn test_retain_and_equality() {
+        let mut ints = Set::new();
+        let mut members = Set::Members(Dict::new());
+        for i in 0..10 {
+            ints.insert(format_int(i));
+            members.insert(format_int(i));
+        }
+        assert_eq!(ints, members);
+        ints.retain(|member| member.as_ref() < b"5".as_slice());
+        assert_eq!(
+            ints.members(),
+            vec![
+                member("0"),
+                member("1"),
+                member("2"),
+                member("3"),
+                member("4")
+            ]
+        );
+        assert_ne!(ints, members);
+    }
+}
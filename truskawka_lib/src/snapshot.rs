@@ -0,0 +1,426 @@
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::future::Future;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::{Buf, BufMut, Bytes};
+
+use crate::db::Object;
+use crate::dict::Dict;
+use crate::set::Set;
+use crate::sorted_set::SortedSet;
+
+const MAGIC: &[u8] = b"TRUSKAWKA";
+const VERSION: u32 = 1;
+
+const STRING_TAG: u8 = 0;
+const LIST_TAG: u8 = 1;
+const HASH_TAG: u8 = 2;
+const SET_TAG: u8 = 3;
+const SORTED_SET_TAG: u8 = 4;
+const END_TAG: u8 = 0xff;
+
+#[derive(thiserror::Error, Debug)]
+pub enum SnapshotError {
+    #[error("IO error")]
+    IOError {
+        #[from]
+        source: std::io::Error,
+    },
+    #[error("snapshots are disabled")]
+    Disabled,
+    #[error("a background save is already in progress")]
+    InProgress,
+    #[error("not a snapshot file")]
+    BadMagic,
+    #[error("unsupported snapshot version {version}")]
+    UnsupportedVersion { version: u32 },
+    #[error("snapshot checksum mismatch")]
+    ChecksumMismatch,
+    #[error("snapshot is truncated")]
+    Truncated,
+    #[error("unknown value type {tag} in snapshot")]
+    UnknownType { tag: u8 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotEntry {
+    pub key: Bytes,
+    pub object: Object,
+    pub expires_at: Option<SystemTime>,
+}
+
+pub struct Snapshotter {
+    path: PathBuf,
+    saving: Arc<AtomicBool>,
+}
+
+impl Snapshotter {
+    pub fn new(path: PathBuf) -> Self {
+        Snapshotter {
+            path,
+            saving: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn load(&self) -> Result<Vec<SnapshotEntry>, SnapshotError> {
+        match fs::read(&self.path) {
+            Ok(data) => decode(&data),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, entries: &[SnapshotEntry]) -> Result<(), SnapshotError> {
+        if self.saving.load(Ordering::Acquire) {
+            return Err(SnapshotError::InProgress);
+        }
+        write(&self.path, entries)?;
+        log::info!("Saved {} keys to {:?}", entries.len(), self.path);
+        Ok(())
+    }
+
+    pub fn save_in_background<F>(
+        &self,
+        capture: impl FnOnce() -> F,
+    ) -> Result<tokio::task::JoinHandle<()>, SnapshotError>
+    where
+        F: Future<Output = Vec<SnapshotEntry>> + Send + 'static,
+    {
+        if self.saving.swap(true, Ordering::AcqRel) {
+            return Err(SnapshotError::InProgress);
+        }
+        let capture = capture();
+        let path = self.path.clone();
+        let saving = self.saving.clone();
+        Ok(tokio::spawn(async move {
+            let entries = capture.await;
+            let keys = entries.len();
+            let target = path.clone();
+            match tokio::task::spawn_blocking(move || write(&target, &entries)).await {
+                Ok(Ok(())) => log::info!("Saved {} keys to {:?} in background", keys, path),
+                Ok(Err(e)) => log::error!("Background save to {:?} failed: {:?}", path, e),
+                Err(e) => log::error!("Background save task failed: {:?}", e),
+            }
+            saving.store(false, Ordering::Release);
+        }))
+    }
+}
+
+pub fn write(path: &Path, entries: &[SnapshotEntry]) -> Result<(), SnapshotError> {
+    let data = encode(entries);
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp_path = path.with_file_name(format!("{}.tmp", file_name));
+    let mut file = File::create(&temp_path)?;
+    file.write_all(&data)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)?;
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+pub fn encode(entries: &[SnapshotEntry]) -> Vec<u8> {
+    let mut dst = Vec::new();
+    dst.put_slice(MAGIC);
+    dst.put_u32(VERSION);
+    for entry in entries {
+        match &entry.object {
+            Object::String(_) => dst.put_u8(STRING_TAG),
+            Object::List(_) => dst.put_u8(LIST_TAG),
+            Object::Hash(_) => dst.put_u8(HASH_TAG),
+            Object::Set(_) => dst.put_u8(SET_TAG),
+            Object::SortedSet(_) => dst.put_u8(SORTED_SET_TAG),
+        }
+        put_string(&mut dst, &entry.key);
+        match entry.expires_at {
+            Some(when) => {
+                dst.put_u8(1);
+                let millis = when.duration_since(UNIX_EPOCH).unwrap_or_default();
+                dst.put_u64(millis.as_millis() as u64);
+            }
+            None => dst.put_u8(0),
+        }
+        match &entry.object {
+            Object::String(string) => put_string(&mut dst, string),
+            Object::List(list) => {
+                dst.put_u32(list.len() as u32);
+                list.iter().for_each(|item| put_string(&mut dst, item));
+            }
+            Object::Hash(hash) => {
+                dst.put_u32(hash.len() as u32);
+                for (field, value) in hash {
+                    put_string(&mut dst, field);
+                    put_string(&mut dst, value);
+                }
+            }
+            Object::Set(set) => {
+                let members = set.members();
+                dst.put_u32(members.len() as u32);
+                members
+                    .iter()
+                    .for_each(|member| put_string(&mut dst, member));
+            }
+            Object::SortedSet(set) => {
+                dst.put_u32(set.len() as u32);
+                for (member, score) in set.range(0..set.len()) {
+                    put_string(&mut dst, &member);
+                    dst.put_f64(score);
+                }
+            }
+        }
+    }
+    dst.put_u8(END_TAG);
+    let checksum = crc32fast::hash(&dst);
+    dst.put_u32(checksum);
+    dst
+}
+
+pub fn decode(data: &[u8]) -> Result<Vec<SnapshotEntry>, SnapshotError> {
+    if data.len() < MAGIC.len() || &data[..MAGIC.len()] != MAGIC {
+        return Err(SnapshotError::BadMagic);
+    }
+    if data.len() < MAGIC.len() + 4 + 1 + 4 {
+        return Err(SnapshotError::Truncated);
+    }
+    let (body, checksum) = data.split_at(data.len() - 4);
+    if crc32fast::hash(body) != u32::from_be_bytes(checksum.try_into().unwrap()) {
+        return Err(SnapshotError::ChecksumMismatch);
+    }
+    let mut src = &body[MAGIC.len()..];
+    let version = src.get_u32();
+    if version != VERSION {
+        return Err(SnapshotError::UnsupportedVersion { version });
+    }
+    let mut entries = Vec::new();
+    loop {
+        let tag = get_u8(&mut src)?;
+        if tag == END_TAG {
+            break;
+        }
+        let key = get_string(&mut src)?;
+        let expires_at = match get_u8(&mut src)? {
+            0 => None,
+            _ => Some(UNIX_EPOCH + Duration::from_millis(get_u64(&mut src)?)),
+        };
+        let object = match tag {
+            STRING_TAG => Object::String(get_string(&mut src)?),
+            LIST_TAG => {
+                let len = get_u32(&mut src)?;
+                let mut list = VecDeque::new();
+                for _ in 0..len {
+                    list.push_back(get_string(&mut src)?);
+                }
+                Object::List(list)
+            }
+            HASH_TAG => {
+                let len = get_u32(&mut src)?;
+                let mut hash = Dict::new();
+                for _ in 0..len {
+                    hash.insert(get_string(&mut src)?, get_string(&mut src)?);
+                }
+                Object::Hash(hash)
+            }
+            SET_TAG => {
+                let len = get_u32(&mut src)?;
+                let mut set = Set::new();
+                for _ in 0..len {
+                    set.insert(get_string(&mut src)?);
+                }
+                Object::Set(set)
+            }
+            SORTED_SET_TAG => {
+                let len = get_u32(&mut src)?;
+                let mut set = SortedSet::new();
+                for _ in 0..len {
+                    let member = get_string(&mut src)?;
+                    set.insert(member, get_f64(&mut src)?);
+                }
+                Object::SortedSet(set)
+            }
+            tag => return Err(SnapshotError::UnknownType { tag }),
+        };
+        entries.push(SnapshotEntry {
+            key,
+            object,
+            expires_at,
+        });
+    }
+    Ok(entries)
+}
+
+fn put_string(dst: &mut Vec<u8>, string: &[u8]) {
+    dst.put_u32(string.len() as u32);
+    dst.put_slice(string);
+}
+
+fn ensure_remaining(src: &[u8], len: usize) -> Result<(), SnapshotError> {
+    match src.remaining() >= len {
+        true => Ok(()),
+        false => Err(SnapshotError::Truncated),
+    }
+}
+
+fn get_u8(src: &mut &[u8]) -> Result<u8, SnapshotError> {
+    ensure_remaining(src, 1)?;
+    Ok(src.get_u8())
+}
+
+fn get_u32(src: &mut &[u8]) -> Result<u32, SnapshotError> {
+    ensure_remaining(src, 4)?;
+    Ok(src.get_u32())
+}
+
+fn get_u64(src: &mut &[u8]) -> Result<u64, SnapshotError> {
+    ensure_remaining(src, 8)?;
+    Ok(src.get_u64())
+}
+
+fn get_f64(src: &mut &[u8]) -> Result<f64, SnapshotError> {
+    ensure_remaining(src, 8)?;
+    Ok(src.get_f64())
+}
+
+fn get_string(src: &mut &[u8]) -> Result<Bytes, SnapshotError> {
+    let len = get_u32(src)? as usize;
+    ensure_remaining(src, len)?;
+    let string = Bytes::copy_from_slice(&src[..len]);
+    src.advance(len);
+    Ok(string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(string: &str) -> Bytes {
+        Bytes::copy_from_slice(string.as_bytes())
+    }
+
+    fn entries() -> Vec<SnapshotEntry> {
+        let mut set = Set::new();
+        set.insert(string("1"));
+        set.insert(string("2"));
+        let mut sorted_set = SortedSet::new();
+        sorted_set.insert(string("a"), 1.5);
+        sorted_set.insert(string("b"), f64::NEG_INFINITY);
+        vec![
+            SnapshotEntry {
+                key: string("string"),
+                object: Object::String(Bytes::from_static(&[0, 0xff, 1])),
+                expires_at: Some(UNIX_EPOCH + Duration::from_millis(4_102_444_800_000)),
+            },
+            SnapshotEntry {
+                key: string("list"),
+                object: Object::List(VecDeque::from([string("x"), string("y")])),
+                expires_at: None,
+            },
+            SnapshotEntry {
+                key: string("hash"),
+                object: Object::Hash(Dict::from_iter([(string("field"), string("value"))])),
+                expires_at: None,
+            },
+            SnapshotEntry {
+                key: string("set"),
+                object: Object::Set(set),
+                expires_at: None,
+            },
+            SnapshotEntry {
+                key: string("zset"),
+                object: Object::SortedSet(sorted_set),
+                expires_at: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let entries = entries();
+        let data = encode(&entries);
+        assert_eq!(&data[..MAGIC.len()], MAGIC);
+        assert_eq!(decode(&data).unwrap(), entries);
+        assert_eq!(decode(&encode(&[])).unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn test_corruption_is_detected() {
+        let mut data = encode(&entries());
+        let middle = data.len() / 2;
+        data[middle] ^= 1;
+        assert!(matches!(
+            decode(&data),
+            Err(SnapshotError::ChecksumMismatch)
+        ));
+        let data = encode(&entries());
+        assert!(matches!(
+            decode(&data[..data.len() - 10]),
+            Err(SnapshotError::ChecksumMismatch)
+        ));
+        assert!(matches!(
+            decode(b"NOT A SNAPSHOT"),
+            Err(SnapshotError::BadMagic)
+        ));
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let mut data = encode(&[]);
+        data[MAGIC.len() + 3] = 2;
+        let len = data.len();
+        let checksum = crc32fast::hash(&data[..len - 4]);
+        data[len - 4..].copy_from_slice(&checksum.to_be_bytes());
+        assert!(matches!(
+            decode(&data),
+            Err(SnapshotError::UnsupportedVersion { version: 2 })
+        ));
+    }
+
+    #[test]
+    fn test_write_replaces_file_atomically() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dump.snapshot");
+        let snapshotter = Snapshotter::new(path.clone());
+        assert_eq!(snapshotter.load().unwrap(), Vec::new());
+        snapshotter.save(&entries()).unwrap();
+        snapshotter.save(&entries()[..2]).unwrap();
+        assert_eq!(snapshotter.load().unwrap(), entries()[..2].to_vec());
+        let files: Vec<_> = fs::read_dir(dir.path()).unwrap().collect();
+        assert_eq!(files.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_only_one_background_save_at_a_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshotter = Snapshotter::new(dir.path().join("dump.snapshot"));
+        snapshotter.saving.store(true, Ordering::Release);
+        assert!(matches!(
+            snapshotter.save_in_background(|| async { entries() }),
+            Err(SnapshotError::InProgress)
+        ));
+        assert!(matches!(
+            snapshotter.save(&entries()),
+            Err(SnapshotError::InProgress)
+        ));
+        snapshotter.saving.store(false, Ordering::Release);
+        let handle = snapshotter
+            .save_in_background(|| async { entries() })
+            .unwrap();
+        handle.await.unwrap();
+        assert_eq!(snapshotter.load().unwrap(), entries());
+        snapshotter
+            .save_in_background(|| async { Vec::new() })
+            .unwrap()
+            .await
+            .unwrap();
+        assert_eq!(snapshotter.load().unwrap(), Vec::new());
+    }
+}
@@ -0,0 +1,442 @@
+use std::cmp::Ordering;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::ops::Range;
+
+use bytes::Bytes;
+
+use crate::dict::Dict;
+
+const NIL: usize = usize::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScoreBound {
+    Inclusive(f64),
+    Exclusive(f64),
+}
+
+impl ScoreBound {
+    fn below(&self, score: f64) -> bool {
+        match *self {
+            ScoreBound::Inclusive(bound) => score < bound,
+            ScoreBound::Exclusive(bound) => score <= bound,
+        }
+    }
+
+    fn not_above(&self, score: f64) -> bool {
+        match *self {
+            ScoreBound::Inclusive(bound) => score <= bound,
+            ScoreBound::Exclusive(bound) => score < bound,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    score: f64,
+    member: Bytes,
+    priority: u64,
+    size: usize,
+    left: usize,
+    right: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Link {
+    Root,
+    Left(usize),
+    Right(usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct SortedSet {
+    scores: Dict<Bytes, f64>,
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    root: usize,
+    seed: u64,
+}
+
+impl Default for SortedSet {
+    fn default() -> Self {
+        SortedSet {
+            scores: Dict::new(),
+            nodes: Vec::new(),
+            free: Vec::new(),
+            root: NIL,
+            seed: RandomState::new().build_hasher().finish(),
+        }
+    }
+}
+
+impl PartialEq for SortedSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.scores == other.scores
+    }
+}
+
+impl SortedSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &Bytes) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    pub fn insert(&mut self, member: Bytes, score: f64) -> bool {
+        let score = score + 0.0;
+        match self.scores.insert(member.clone(), score) {
+            Some(previous) if previous.total_cmp(&score).is_eq() => false,
+            Some(previous) => {
+                self.unlink(previous, &member);
+                self.link(score, member);
+                false
+            }
+            None => {
+                self.link(score, member);
+                true
+            }
+        }
+    }
+
+    pub fn remove(&mut self, member: &Bytes) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => {
+                self.unlink(score, member);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn rank(&self, member: &Bytes) -> Option<usize> {
+        let score = self.score(member)?;
+        Some(self.count_while(|node| compare(node.score, &node.member, score, member).is_lt()))
+    }
+
+    pub fn rank_range(&self, min: ScoreBound, max: ScoreBound) -> Range<usize> {
+        let start = self.count_while(|node| min.below(node.score));
+        let end = self.count_while(|node| max.not_above(node.score));
+        start..end.max(start)
+    }
+
+    pub fn range(&self, ranks: Range<usize>) -> Vec<(Bytes, f64)> {
+        let mut entries = Vec::with_capacity(ranks.len().min(self.len()));
+        let mut stack = Vec::new();
+        let mut current = self.root;
+        let mut skip = ranks.start;
+        while current != NIL {
+            let node = &self.nodes[current];
+            let left_size = self.size(node.left);
+            match skip.cmp(&left_size) {
+                Ordering::Less => {
+                    stack.push(current);
+                    current = node.left;
+                }
+                Ordering::Equal => {
+                    stack.push(current);
+                    break;
+                }
+                Ordering::Greater => {
+                    skip -= left_size + 1;
+                    current = node.right;
+                }
+            }
+        }
+        while entries.len() < ranks.len() {
+            let Some(index) = stack.pop() else {
+                break;
+            };
+            let node = &self.nodes[index];
+            entries.push((node.member.clone(), node.score));
+            let mut current = node.right;
+            while current != NIL {
+                stack.push(current);
+                current = self.nodes[current].left;
+            }
+        }
+        entries
+    }
+
+    pub fn scan(&self, cursor: u64, count: usize, mut f: impl FnMut(&Bytes, f64)) -> u64 {
+        self.scores
+            .scan_batch(cursor, count, |member, score| f(member, *score))
+    }
+
+    fn count_while(&self, before: impl Fn(&Node) -> bool) -> usize {
+        let mut count = 0;
+        let mut current = self.root;
+        while current != NIL {
+            let node = &self.nodes[current];
+            if before(node) {
+                count += self.size(node.left) + 1;
+                current = node.right;
+            } else {
+                current = node.left;
+            }
+        }
+        count
+    }
+
+    fn link(&mut self, score: f64, member: Bytes) {
+        let (left, right) = self.split(self.root, score, &member);
+        let node = self.allocate(score, member);
+        let left = self.merge(left, node);
+        self.root = self.merge(left, right);
+    }
+
+    fn unlink(&mut self, score: f64, member: &Bytes) {
+        let (left, right) = self.split(self.root, score, member);
+        let (node, right) = self.split_first(right);
+        debug_assert!(node != NIL && self.nodes[node].member == *member);
+        if node != NIL {
+            self.nodes[node].member = Bytes::new();
+            self.free.push(node);
+        }
+        self.root = self.merge(left, right);
+    }
+
+    fn allocate(&mut self, score: f64, member: Bytes) -> usize {
+        self.seed = self.seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut priority = self.seed;
+        priority = (priority ^ (priority >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        priority = (priority ^ (priority >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        let node = Node {
+            score,
+            member,
+            priority: priority ^ (priority >> 31),
+            size: 1,
+            left: NIL,
+            right: NIL,
+        };
+        match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = node;
+                index
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    fn size(&self, node: usize) -> usize {
+        if node == NIL {
+            0
+        } else {
+            self.nodes[node].size
+        }
+    }
+
+    fn update_size(&mut self, node: usize) {
+        let size = self.size(self.nodes[node].left) + self.size(self.nodes[node].right) + 1;
+        self.nodes[node].size = size;
+    }
+
+    fn set_link(&mut self, link: Link, root: &mut usize, child: usize) {
+        match link {
+            Link::Root => *root = child,
+            Link::Left(parent) => self.nodes[parent].left = child,
+            Link::Right(parent) => self.nodes[parent].right = child,
+        }
+    }
+
+    fn update_sizes(&mut self, path: &[usize]) {
+        for &node in path.iter().rev() {
+            self.update_size(node);
+        }
+    }
+
+    fn split(&mut self, mut node: usize, score: f64, member: &Bytes) -> (usize, usize) {
+        let (mut left, mut right) = (NIL, NIL);
+        let (mut left_link, mut right_link) = (Link::Root, Link::Root);
+        let mut path = Vec::new();
+        while node != NIL {
+            path.push(node);
+            let current = &self.nodes[node];
+            if compare(current.score, &current.member, score, member).is_lt() {
+                self.set_link(left_link, &mut left, node);
+                left_link = Link::Right(node);
+                node = self.nodes[node].right;
+            } else {
+                self.set_link(right_link, &mut right, node);
+                right_link = Link::Left(node);
+                node = self.nodes[node].left;
+            }
+        }
+        self.set_link(left_link, &mut left, NIL);
+        self.set_link(right_link, &mut right, NIL);
+        self.update_sizes(&path);
+        (left, right)
+    }
+
+    fn split_first(&mut self, node: usize) -> (usize, usize) {
+        if node == NIL {
+            return (NIL, NIL);
+        }
+        let mut path = Vec::new();
+        let mut first = node;
+        while self.nodes[first].left != NIL {
+            path.push(first);
+            first = self.nodes[first].left;
+        }
+        let rest = self.nodes[first].right;
+        self.nodes[first].right = NIL;
+        self.update_size(first);
+        let Some(&parent) = path.last() else {
+            return (first, rest);
+        };
+        self.nodes[parent].left = rest;
+        self.update_sizes(&path);
+        (first, node)
+    }
+
+    fn merge(&mut self, mut left: usize, mut right: usize) -> usize {
+        let mut root = NIL;
+        let mut link = Link::Root;
+        let mut path = Vec::new();
+        while left != NIL && right != NIL {
+            if self.nodes[left].priority > self.nodes[right].priority {
+                self.set_link(link, &mut root, left);
+                path.push(left);
+                link = Link::Right(left);
+                left = self.nodes[left].right;
+            } else {
+                self.set_link(link, &mut root, right);
+                path.push(right);
+                link = Link::Left(right);
+                right = self.nodes[right].left;
+            }
+        }
+        let rest = if left == NIL { right } else { left };
+        self.set_link(link, &mut root, rest);
+        self.update_sizes(&path);
+        root
+    }
+}
+
+fn compare(score: f64, member: &Bytes, other_score: f64, other_member: &Bytes) -> Ordering {
+    score
+        .total_cmp(&other_score)
+        .then_with(|| member.cmp(other_member))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(i: usize) -> Bytes {
+        Bytes::from(format!("member{:05}", i))
+    }
+
+    #[test]
+    fn test_insert_update_and_remove() {
+        let mut set = SortedSet::new();
+        assert!(set.insert(member(1), 2.0));
+        assert!(set.insert(member(2), 1.0));
+        assert!(!set.insert(member(1), 0.5));
+        assert_eq!(set.len(), 2);
+        assert_eq!(set.score(&member(1)), Some(0.5));
+        assert_eq!(set.rank(&member(1)), Some(0));
+        assert_eq!(set.rank(&member(2)), Some(1));
+        assert!(set.remove(&member(1)));
+        assert!(!set.remove(&member(1)));
+        assert_eq!(set.rank(&member(2)), Some(0));
+        assert_eq!(set.range(0..10), vec![(member(2), 1.0)]);
+    }
+
+    #[test]
+    fn test_equal_scores_are_ordered_by_member() {
+        let mut set = SortedSet::new();
+        set.insert(Bytes::from_static(b"c"), 1.0);
+        set.insert(Bytes::from_static(b"a"), 1.0);
+        set.insert(Bytes::from_static(b"b"), 1.0);
+        set.insert(Bytes::from_static(b"z"), -0.0);
+        let members: Vec<_> = set.range(0..4).into_iter().map(|(m, _)| m).collect();
+        assert_eq!(members, vec!["z", "a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_ranks_and_ranges_match_sorted_order() {
+        let mut set = SortedSet::new();
+        let mut expected = Vec::new();
+        for i in 0..2000 {
+            let score = ((i * 7919) % 1000) as f64;
+            set.insert(member(i), score);
+            expected.push((member(i), score));
+        }
+        for i in (0..2000).step_by(3) {
+            set.remove(&member(i));
+        }
+        expected.retain(|(m, _)| set.score(m).is_some());
+        expected.sort_by(|(a, x), (b, y)| compare(*x, a, *y, b));
+        assert_eq!(set.len(), expected.len());
+        assert_eq!(set.range(0..set.len()), expected);
+        assert_eq!(set.range(100..150), expected[100..150].to_vec());
+        for (rank, (m, _)) in expected.iter().enumerate() {
+            assert_eq!(set.rank(m), Some(rank));
+        }
+    }
+
+    fn depth(set: &SortedSet) -> usize {
+        let mut deepest = 0;
+        let mut stack = vec![(set.root, 1)];
+        while let Some((node, depth)) = stack.pop() {
+            if node == NIL {
+                continue;
+            }
+            deepest = deepest.max(depth);
+            stack.push((set.nodes[node].left, depth + 1));
+            stack.push((set.nodes[node].right, depth + 1));
+        }
+        deepest
+    }
+
+    #[test]
+    fn test_depth_stays_bounded_for_increasing_scores() {
+        let mut set = SortedSet::new();
+        for i in 0..100_000 {
+            set.insert(member(i), i as f64);
+        }
+        assert!(depth(&set) < 100, "depth {}", depth(&set));
+        for i in (0..100_000).step_by(2) {
+            set.remove(&member(i));
+        }
+        assert!(depth(&set) < 100, "depth {}", depth(&set));
+        assert_eq!(set.rank(&member(99_999)), Some(49_999));
+    }
+
+    #[test]
+    fn test_sets_use_different_priority_seeds() {
+        assert_ne!(SortedSet::new().seed, SortedSet::new().seed);
+    }
+
+    #[test]
+    fn test_rank_range_by_score() {
+        let mut set = SortedSet::new();
+        for i in 0..10 {
+            set.insert(member(i), i as f64);
+        }
+        let range = set.rank_range(ScoreBound::Inclusive(2.0), ScoreBound::Inclusive(5.0));
+        assert_eq!(range, 2..6);
+        let range = set.rank_range(ScoreBound::Exclusive(2.0), ScoreBound::Exclusive(5.0));
+        assert_eq!(range, 3..5);
+        let range = set.rank_range(
+            ScoreBound::Inclusive(f64::NEG_INFINITY),
+            ScoreBound::Inclusive(f64::INFINITY),
+        );
+        assert_eq!(range, 0..10);
+        let range = set.rank_range(ScoreBound::Inclusive(7.0), ScoreBound::Inclusive(3.0));
+        assert!(range.is_empty());
+    }
+}